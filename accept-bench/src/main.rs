@@ -0,0 +1,223 @@
+//! Compare TLS connections per second of a single accept loop against sharded
+//! `SO_REUSEPORT` accept loops.
+//!
+//! Usage: `cargo run --release -p accept-bench -- [shards] [clients] [secs]`
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rustls::{
+    Certificate, ClientConfig, NoClientAuth, RootCertStore, ServerCertVerified, ServerCertVerifier,
+    ServerConfig, TLSError,
+};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+
+use socket2::SockRef;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::watch;
+
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_rustls::webpki::DNSNameRef;
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+// Same certificate as rustls-server serves.
+const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../rustls-server/certs/key.pem");
+const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../rustls-server/certs/cert.pem");
+
+fn main() -> Result<(), AnyError> {
+    let mut args = std::env::args().skip(1);
+
+    let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+    let shards = parse_arg(args.next(), cores)?;
+    let clients = parse_arg(args.next(), 64)?;
+    let secs = parse_arg(args.next(), 5)?;
+
+    let acceptor = TlsAcceptor::from(Arc::new(server_config()?));
+
+    println!("{} clients, {} seconds per run, {} cores", clients, secs, cores);
+
+    let single = run(&acceptor, 1, clients, secs)?;
+    println!("single acceptor:     {:>10.0} conn/s", single);
+
+    let sharded = run(&acceptor, shards, clients, secs)?;
+    println!("{:>3} sharded accepts: {:>10.0} conn/s", shards, sharded);
+
+    println!("speedup: {:.2}x", sharded / single);
+
+    Ok(())
+}
+
+/// Run a fresh server with `shards` listeners and hammer it with handshakes.
+fn run(acceptor: &TlsAcceptor, shards: usize, clients: usize, secs: u64) -> Result<f64, AnyError> {
+    // Clients get a runtime of their own so they don't steal server's workers.
+    let client_rt = runtime()?;
+
+    let listeners = accept_shards::bind("127.0.0.1:0".parse().unwrap(), shards)?;
+    let addr = listeners[0].local_addr()?;
+
+    let (stop_server, server_stop) = watch::channel(());
+
+    // Same as rustls-server: a single listener is accepted on a multi-threaded runtime and
+    // shards on threads of their own.
+    let (server_rt, shard_threads) = match shards {
+        1 => {
+            let rt = runtime()?;
+            let listener = rt.block_on(async { TcpListener::from_std(listeners.into_iter().next().unwrap()) })?;
+
+            rt.spawn(accept_loop(listener, acceptor.clone(), server_stop));
+
+            (Some(rt), Vec::new())
+        },
+        _ => {
+            let threads = listeners.into_iter()
+                .map(|listener| {
+                    let acceptor = acceptor.clone();
+                    let stop = server_stop.clone();
+
+                    accept_shards::spawn_pinned(listener, move |listener| accept_loop(listener, acceptor, stop))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            (None, threads)
+        },
+    };
+
+    let connector = TlsConnector::from(Arc::new(client_config()));
+    let done = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let start = Instant::now();
+
+    client_rt.block_on(async {
+        let tasks: Vec<_> = (0..clients)
+            .map(|_| tokio::spawn(client_loop(addr, connector.clone(), done.clone(), stop.clone())))
+            .collect();
+
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+        stop.store(true, Ordering::Relaxed);
+
+        for task in tasks {
+            let _ = task.await;
+        }
+    });
+
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let _ = stop_server.send(());
+
+    if let Some(rt) = server_rt {
+        rt.shutdown_background();
+    }
+
+    for thread in shard_threads {
+        let _ = thread.join();
+    }
+
+    Ok(done.load(Ordering::Relaxed) as f64 / elapsed)
+}
+
+// Same shape as server: accept then finish handshake in a separate task.
+async fn accept_loop(listener: TcpListener, acceptor: TlsAcceptor, mut stop: watch::Receiver<()>) {
+    loop {
+        let stream = tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => stream,
+                Err(_) => continue,
+            },
+            _ = stop.changed() => return,
+        };
+
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            let _ = acceptor.accept(stream).await;
+        });
+    }
+}
+
+async fn client_loop(
+    addr: SocketAddr,
+    connector: TlsConnector,
+    done: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+) {
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+
+    while !stop.load(Ordering::Relaxed) {
+        let stream = match TcpStream::connect(addr).await {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+
+        // Reset instead of FIN so closed connections don't use up local ports.
+        // Zero timeout never blocks on close.
+        let _ = SockRef::from(&stream).set_linger(Some(Duration::from_secs(0)));
+
+        if connector.connect(domain, stream).await.is_ok() {
+            done.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn runtime() -> Result<Runtime, AnyError> {
+    Ok(Builder::new_multi_thread().enable_all().build()?)
+}
+
+fn server_config() -> Result<ServerConfig, AnyError> {
+    let open = |path| File::open(path).map_err(|_| format!("Can't open {:?}.", path));
+
+    let chain = certs(&mut BufReader::new(open(CERT)?))
+        .map_err(|_| "Invalid certificate chain.")?;
+
+    let key = pkcs8_private_keys(&mut BufReader::new(open(KEY)?))
+        .map_err(|_| "Invalid private key.")?
+        .into_iter()
+        .next()
+        .ok_or("No private key found.")?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+
+    config.set_single_cert(chain, key)?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+
+    Ok(config)
+}
+
+fn client_config() -> ClientConfig {
+    let mut config = ClientConfig::new();
+
+    config.root_store = RootCertStore::empty();
+    config.dangerous().set_certificate_verifier(Arc::new(NoVerify));
+
+    config
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: Option<String>, default: T) -> Result<T, AnyError> {
+    match arg {
+        Some(arg) => arg.parse().map_err(|_| format!("Invalid argument {:?}.", arg).into()),
+        None => Ok(default),
+    }
+}
+
+// Test certificate is self-signed, handshake cost is what's measured.
+struct NoVerify;
+
+impl ServerCertVerifier for NoVerify {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,106 @@
+//! `SO_REUSEPORT` listeners that are accepted on threads of their own.
+
+use std::future::Future;
+use std::io;
+use std::net::{self, SocketAddr};
+use std::thread::{self, JoinHandle};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use tokio::net::TcpListener;
+use tokio::runtime::Builder;
+
+/// Bind `count` listeners to `addr`.
+///
+/// Listeners share the port with `SO_REUSEPORT`, so kernel spreads new connections across them.
+/// They are nonblocking and not registered with a runtime yet, see [`spawn_pinned`].
+pub fn bind(addr: SocketAddr, count: usize) -> io::Result<Vec<net::TcpListener>> {
+    let first = bind_one(addr, count > 1)?;
+
+    // Port is picked by first bind when it is 0.
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+
+    for _ in 1..count {
+        listeners.push(bind_one(addr, true)?);
+    }
+
+    Ok(listeners)
+}
+
+/// Run `serve` with `listener` on a new thread that has a single-threaded runtime.
+///
+/// Tasks spawned by `serve` stay on that thread. Thread exits once `serve` returns, dropping
+/// tasks that are still running.
+pub fn spawn_pinned<F, Fut>(listener: net::TcpListener, serve: F) -> io::Result<JoinHandle<()>>
+where
+    F: FnOnce(TcpListener) -> Fut + Send + 'static,
+    Fut: Future<Output = ()>,
+{
+    let rt = Builder::new_current_thread().enable_all().build()?;
+
+    // Listener has to be registered with the runtime that polls it.
+    let listener = {
+        let _guard = rt.enter();
+
+        TcpListener::from_std(listener)?
+    };
+
+    thread::Builder::new()
+        .name("accept-shard".into())
+        .spawn(move || rt.block_on(serve(listener)))
+}
+
+fn bind_one(addr: SocketAddr, reuse_port: bool) -> io::Result<net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // Same as tokio does for TcpListener::bind.
+    socket.set_reuse_address(true)?;
+
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_share_port() {
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), 3).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+
+        assert_eq!(listeners.len(), 3);
+        assert!(listeners.iter().all(|l| l.local_addr().unwrap() == addr));
+    }
+
+    #[test]
+    fn serves_on_own_thread() {
+        let listener = bind("127.0.0.1:0".parse().unwrap(), 1).unwrap().remove(0);
+        let addr = listener.local_addr().unwrap();
+        let caller = thread::current().id();
+
+        let handle = spawn_pinned(listener, move |listener| async move {
+            assert_ne!(thread::current().id(), caller);
+
+            let (_stream, peer) = listener.accept().await.unwrap();
+
+            // Spawned tasks run on the same thread.
+            let task = tokio::spawn(async move { (thread::current().id(), peer) });
+            let (task_thread, _) = task.await.unwrap();
+
+            assert_eq!(task_thread, thread::current().id());
+        }).unwrap();
+
+        let _client = net::TcpStream::connect(addr).unwrap();
+
+        handle.join().unwrap();
+    }
+}
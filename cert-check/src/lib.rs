@@ -0,0 +1,399 @@
+//! Checks of certificate chains and keys before servers use them.
+
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use rustls::{Certificate, OwnedTrustAnchor, SignatureScheme};
+use rustls::sign::CertifiedKey;
+
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::time::ASN1Time;
+
+// One scheme for every supported key type.
+const KEY_CHECK_SCHEMES: [SignatureScheme; 4] = [
+    SignatureScheme::RSA_PKCS1_SHA256,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ED25519,
+];
+
+static CHAIN_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Problems that make `keys` unfit to serve `name`, empty when there are none.
+///
+/// Problems of a domain with several keys, like RSA and ECDSA, say which key they are about.
+pub fn check_keys(name: &str, keys: &[CertifiedKey]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (i, key) in keys.iter().enumerate() {
+        let prefix = match keys.len() {
+            1 => String::new(),
+            _ => format!("Certificate {}: ", i),
+        };
+
+        problems.extend(check_key(key, &[name]).into_iter().map(|p| format!("{}{}", prefix, p)));
+    }
+
+    problems
+}
+
+/// Problems that make `key` unfit to serve `names`, empty when there are none.
+///
+/// Private key must match leaf certificate, every certificate must be followed by
+/// its issuer and last one must be self-signed or issued by a trusted root. Leaf
+/// must be currently valid and cover every name, wildcards included.
+pub fn check_key(key: &CertifiedKey, names: &[&str]) -> Vec<String> {
+    let chain = match parse_chain(&key.cert) {
+        Ok(chain) => chain,
+        Err(problem) => return vec![problem],
+    };
+
+    let mut problems = Vec::new();
+
+    problems.extend(check_key_matches(key).err());
+    problems.extend(check_chain(&key.cert, &chain).err());
+    problems.extend(check_validity(&chain[0]).err());
+    problems.extend(check_names(&chain[0], names).err());
+
+    problems
+}
+
+/// Dns subject alternative names of leaf certificate of `key`.
+///
+/// Common name isn't used, clients ignore it too.
+pub fn certificate_names(key: &CertifiedKey) -> Vec<String> {
+    match key.cert.first().map(|cert| x509_parser::parse_x509_certificate(&cert.0)) {
+        Some(Ok((_, leaf))) => dns_names(&leaf),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_chain(certs: &[Certificate]) -> Result<Vec<X509Certificate<'_>>, String> {
+    if certs.is_empty() {
+        return Err("Certificate chain is empty.".to_owned());
+    }
+
+    certs.iter()
+        .enumerate()
+        .map(|(i, cert)| match x509_parser::parse_x509_certificate(&cert.0) {
+            Ok((_, cert)) => Ok(cert),
+            Err(_) => Err(format!("Can't parse certificate {} in chain.", i)),
+        })
+        .collect()
+}
+
+// Sign a message with private key and verify it with leaf certificate.
+fn check_key_matches(key: &CertifiedKey) -> Result<(), String> {
+    const MESSAGE: &[u8] = b"private key check";
+
+    let signer = key.key.choose_scheme(&KEY_CHECK_SCHEMES)
+        .ok_or("Private key type is not supported.")?;
+
+    let signature = signer.sign(MESSAGE)
+        .map_err(|_| "Can't sign with private key.")?;
+
+    let alg = match signer.get_scheme() {
+        SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => return Err("Private key type is not supported.".to_owned()),
+    };
+
+    let leaf = webpki::EndEntityCert::from(&key.cert[0].0)
+        .map_err(|_| "Leaf certificate is syntactically invalid.")?;
+
+    leaf.verify_signature(alg, MESSAGE, &signature)
+        .map_err(|_| "Private key doesn't match leaf certificate.")?;
+
+    Ok(())
+}
+
+// Every certificate must be followed by its issuer, and last one must be
+// self-signed or issued by a trusted root.
+fn check_chain(certs: &[Certificate], chain: &[X509Certificate<'_>]) -> Result<(), String> {
+    for (i, pair) in chain.windows(2).enumerate() {
+        let (cert, issuer) = (&pair[0], &pair[1]);
+
+        if cert.issuer().as_raw() != issuer.subject().as_raw() {
+            let in_chain = chain.iter()
+                .any(|c| c.subject().as_raw() == cert.issuer().as_raw());
+
+            return Err(match in_chain {
+                true => format!(
+                    "Certificate chain is not ordered: certificate {} is not followed by its issuer \"{}\".",
+                    i, cert.issuer()
+                ),
+                false => format!(
+                    "Certificate chain is incomplete: issuer \"{}\" of certificate {} is missing.",
+                    cert.issuer(), i
+                ),
+            });
+        }
+
+        if cert.verify_signature(Some(issuer.public_key())).is_err() {
+            return Err(format!("Certificate {} in chain is not signed by certificate {}.", i, i + 1));
+        }
+    }
+
+    let last = &chain[chain.len() - 1];
+
+    if last.subject().as_raw() == last.issuer().as_raw() {
+        return Ok(());
+    }
+
+    let anchors: Vec<_> = native_roots().iter()
+        .map(OwnedTrustAnchor::to_trust_anchor)
+        .collect();
+
+    let intermediates: Vec<&[u8]> = certs[1..].iter()
+        .map(|cert| cert.0.as_slice())
+        .collect();
+
+    let leaf = webpki::EndEntityCert::from(&certs[0].0)
+        .map_err(|_| "Leaf certificate is syntactically invalid.")?;
+
+    let now = webpki::Time::try_from(SystemTime::now())
+        .map_err(|_| "System time is invalid.")?;
+
+    let result = leaf.verify_is_valid_tls_server_cert(
+        CHAIN_SIG_ALGS,
+        &webpki::TLSServerTrustAnchors(&anchors),
+        &intermediates,
+        now,
+    );
+
+    match result {
+        // Validity period is reported on its own.
+        Ok(()) | Err(webpki::Error::CertExpired) | Err(webpki::Error::CertNotValidYet) => Ok(()),
+        Err(e) => Err(format!(
+            "Certificate chain is incomplete: issuer \"{}\" of last certificate is not a trusted root ({:?}).",
+            last.issuer(), e
+        )),
+    }
+}
+
+fn check_validity(leaf: &X509Certificate<'_>) -> Result<(), String> {
+    let now = ASN1Time::now();
+    let validity = leaf.validity();
+
+    if now < validity.not_before {
+        return Err(format!("Leaf certificate is not valid before {}.", validity.not_before.to_rfc2822()));
+    }
+
+    if now > validity.not_after {
+        return Err(format!("Leaf certificate expired at {}.", validity.not_after.to_rfc2822()));
+    }
+
+    Ok(())
+}
+
+fn check_names(leaf: &X509Certificate<'_>, names: &[&str]) -> Result<(), String> {
+    let cert_names = dns_names(leaf);
+
+    for name in names {
+        if cert_names.iter().any(|cert_name| covers(cert_name, name)) {
+            continue;
+        }
+
+        return Err(match cert_names.is_empty() {
+            true => format!("Leaf certificate has no dns subject alternative names, {:?} is not covered.", name),
+            false => format!("Leaf certificate names ({}) don't cover {:?}.", cert_names.join(", "), name),
+        });
+    }
+
+    Ok(())
+}
+
+// Whether certificate name, maybe a wildcard, covers `name`. A wildcard can be
+// checked too, "*.example.com" is only covered by itself.
+fn covers(cert_name: &str, name: &str) -> bool {
+    let cert_name = cert_name.trim_end_matches('.').to_ascii_lowercase();
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    if cert_name == name {
+        return true;
+    }
+
+    // Leftmost label only, and never over a single label like "*.com".
+    match (cert_name.strip_prefix("*."), name.split_once('.')) {
+        (Some(suffix), Some((label, rest))) => {
+            !label.is_empty() && label != "*" && rest == suffix && suffix.contains('.')
+        },
+        _ => false,
+    }
+}
+
+fn dns_names(leaf: &X509Certificate<'_>) -> Vec<String> {
+    match leaf.subject_alternative_name() {
+        Ok(Some(san)) => san.value.general_names.iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Loaded once, every certificate is checked against same roots.
+fn native_roots() -> &'static [OwnedTrustAnchor] {
+    static ROOTS: OnceLock<Vec<OwnedTrustAnchor>> = OnceLock::new();
+
+    ROOTS.get_or_init(|| match rustls_native_certs::load_native_certs() {
+        Ok(store) => store.roots,
+        Err((Some(store), _)) => store.roots,
+        Err(_) => Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+    use rustls::sign::any_supported_type;
+
+    // Fixture chain in certs, leaf covers "localhost" and "*.example.com".
+    fn key(key: &str, cert: &str) -> CertifiedKey {
+        let pem = std::fs::read(format!("certs/{}", key)).unwrap();
+        let privkey = pkcs8_private_keys(&mut pem.as_slice()).unwrap().remove(0);
+
+        let pem = std::fs::read(format!("certs/{}", cert)).unwrap();
+        let chain = certs(&mut pem.as_slice()).unwrap();
+
+        CertifiedKey::new(chain, Arc::new(any_supported_type(&privkey).unwrap()))
+    }
+
+    #[test]
+    fn valid_chain() {
+        let keys = [key("leaf.key.pem", "chain.pem")];
+
+        assert!(check_keys("localhost", &keys).is_empty());
+        assert!(check_keys("www.example.com", &keys).is_empty());
+        assert!(check_keys("*.example.com", &keys).is_empty());
+        assert!(check_key(&keys[0], &["localhost", "WWW.example.com."]).is_empty());
+    }
+
+    #[test]
+    fn name_not_covered() {
+        let keys = [key("leaf.key.pem", "chain.pem")];
+
+        for name in ["example.com", "a.b.example.com", "localtest"] {
+            assert_eq!(
+                check_keys(name, &keys),
+                [format!("Leaf certificate names (localhost, *.example.com) don't cover {:?}.", name)],
+            );
+        }
+    }
+
+    #[test]
+    fn common_name_ignored() {
+        let keys = [key("leaf.key.pem", "common-name-only.pem")];
+
+        assert!(certificate_names(&keys[0]).is_empty());
+        assert_eq!(
+            check_keys("localhost", &keys),
+            ["Leaf certificate has no dns subject alternative names, \"localhost\" is not covered."],
+        );
+    }
+
+    #[test]
+    fn problems_name_key() {
+        let keys = [key("leaf.key.pem", "chain.pem"), key("leaf.key.pem", "common-name-only.pem")];
+
+        assert_eq!(
+            check_keys("localhost", &keys),
+            ["Certificate 1: Leaf certificate has no dns subject alternative names, \"localhost\" is not covered."],
+        );
+    }
+
+    #[test]
+    fn wildcard_coverage() {
+        assert!(covers("*.example.com", "api.example.com"));
+        assert!(covers("*.EXAMPLE.com", "api.example.com."));
+        assert!(covers("*.example.com", "*.example.com"));
+
+        assert!(!covers("*.example.com", "example.com"));
+        assert!(!covers("*.example.com", "a.b.example.com"));
+        assert!(!covers("*.com", "example.com"));
+        assert!(!covers("api.example.com", "*.example.com"));
+    }
+
+    #[test]
+    fn expired() {
+        let problems = check_keys("localhost", &[key("leaf.key.pem", "expired.pem")]);
+
+        assert_eq!(problems, ["Leaf certificate expired at Fri, 01 Jan 2021 00:00:00 +0000."]);
+    }
+
+    #[test]
+    fn not_yet_valid() {
+        let problems = check_keys("localhost", &[key("leaf.key.pem", "not-yet-valid.pem")]);
+
+        assert_eq!(problems, ["Leaf certificate is not valid before Sun, 01 Jan 2040 00:00:00 +0000."]);
+    }
+
+    #[test]
+    fn wrong_chain_order() {
+        let problems = check_keys("localhost", &[key("leaf.key.pem", "chain-unordered.pem")]);
+
+        assert_eq!(problems, [
+            "Certificate chain is not ordered: certificate 0 is not followed by its issuer \"CN=Test Intermediate CA\".",
+        ]);
+    }
+
+    #[test]
+    fn issuer_missing() {
+        let problems = check_keys("localhost", &[key("leaf.key.pem", "chain-missing.pem")]);
+
+        assert_eq!(problems, [
+            "Certificate chain is incomplete: issuer \"CN=Test Intermediate CA\" of certificate 0 is missing.",
+        ]);
+    }
+
+    // Root isn't in chain or in system trust store.
+    #[test]
+    fn untrusted_root() {
+        let problems = check_keys("localhost", &[key("leaf.key.pem", "chain-untrusted.pem")]);
+
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with(
+            "Certificate chain is incomplete: issuer \"CN=Test Root CA\" of last certificate is not a trusted root (",
+        ), "{}", problems[0]);
+    }
+
+    #[test]
+    fn key_mismatch() {
+        let problems = check_keys("localhost", &[key("other.key.pem", "chain.pem")]);
+
+        assert_eq!(problems, ["Private key doesn't match leaf certificate."]);
+    }
+
+    #[test]
+    fn every_problem_reported() {
+        let problems = check_key(&key("other.key.pem", "expired.pem"), &["localtest"]);
+
+        assert_eq!(problems, [
+            "Private key doesn't match leaf certificate.",
+            "Leaf certificate expired at Fri, 01 Jan 2021 00:00:00 +0000.",
+            "Leaf certificate names (localhost) don't cover \"localtest\".",
+        ]);
+    }
+}
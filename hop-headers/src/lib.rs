@@ -0,0 +1,69 @@
+//! Hop-by-hop headers that proxies don't pass on.
+
+use hyper::HeaderMap;
+use hyper::header::CONNECTION;
+
+// SEE: https://datatracker.ietf.org/doc/html/rfc7230#section-6.1
+pub const HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Remove [`HOP_HEADERS`] and headers named in "connection" from `headers`.
+pub fn remove_hop_headers(headers: &mut HeaderMap) {
+    // Headers listed in "connection" are hop-by-hop too.
+    let listed: Vec<String> = headers.get_all(CONNECTION).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+
+    for name in listed.iter().map(String::as_str).chain(HOP_HEADERS) {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn removes_hop_headers() {
+        let mut headers = HeaderMap::new();
+
+        for name in HOP_HEADERS {
+            headers.insert(name, HeaderValue::from_static("1"));
+        }
+
+        headers.insert("x-kept", HeaderValue::from_static("1"));
+
+        remove_hop_headers(&mut headers);
+
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("x-kept"));
+    }
+
+    #[test]
+    fn removes_listed_headers() {
+        let mut headers = HeaderMap::new();
+
+        headers.append(CONNECTION, HeaderValue::from_static("keep-alive, X-Listed"));
+        headers.append(CONNECTION, HeaderValue::from_static("x-other"));
+        headers.insert("x-listed", HeaderValue::from_static("1"));
+        headers.insert("x-other", HeaderValue::from_static("1"));
+        headers.insert("x-kept", HeaderValue::from_static("1"));
+
+        remove_hop_headers(&mut headers);
+
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("x-kept"));
+    }
+}
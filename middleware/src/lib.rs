@@ -0,0 +1,313 @@
+//! Standard tower layer stack shared by hyper servers.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use http_body::combinators::UnsyncBoxBody;
+
+use hyper::{Body, Request, Response};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue};
+
+use serde::Deserialize;
+
+use tower::{Layer, Service, ServiceBuilder, ServiceExt};
+use tower::limit::ConcurrencyLimitLayer;
+use tower::util::BoxCloneService;
+
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::timeout::TimeoutLayer;
+use tower_http::trace::TraceLayer;
+
+use tracing::Span;
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type BoxBody = UnsyncBoxBody<Bytes, AnyError>;
+pub type BoxService = BoxCloneService<Request<Body>, Response<BoxBody>, Infallible>;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longer or unusual ids are replaced, they end up in logs and upstream requests.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Settings of [`stack`], read from "middleware" of a server config.
+#[derive(Deserialize)]
+pub struct MiddlewareConfig {
+    // Requests that take longer get "408 Request Timeout". Must be at least 1.
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+    // Maximum number of requests handled at once by all connections. Must be at least 1.
+    #[serde(default = "default_concurrency_limit")]
+    concurrency_limit: usize,
+    // "*" allows every origin. CORS headers aren't sent when empty.
+    #[serde(default)]
+    cors_allow_origins: Vec<String>,
+}
+
+impl Default for MiddlewareConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_timeout(),
+            concurrency_limit: default_concurrency_limit(),
+            cors_allow_origins: Vec::new(),
+        }
+    }
+}
+
+impl MiddlewareConfig {
+    pub fn validate(&self) -> Result<(), AnyError> {
+        if self.timeout_secs == 0 {
+            return Err("middleware.timeout_secs must be at least 1.".into());
+        }
+
+        if self.concurrency_limit == 0 {
+            return Err("middleware.concurrency_limit must be at least 1.".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Wrap `service` with standard layer stack.
+///
+/// Panics inside `service` are turned into "500 Internal Server Error" responses.
+/// Every request gets an `x-request-id`, a valid incoming one is kept.
+pub fn stack<S, B>(service: S, config: &MiddlewareConfig) -> Result<BoxService, AnyError>
+where
+    S: Service<Request<Body>, Response = Response<B>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Default + Send + 'static,
+    B::Error: Into<AnyError> + std::fmt::Display,
+{
+    config.validate()?;
+
+    let service = ServiceBuilder::new()
+        .layer(TimeoutLayer::new(Duration::from_secs(config.timeout_secs)))
+        .layer(ConcurrencyLimitLayer::new(config.concurrency_limit))
+        .service(service);
+
+    // Not layered at all without origins, CorsLayer would answer every OPTIONS request itself.
+    // Option layer isn't used since its Either service boxes Infallible errors.
+    match cors_layer(&config.cors_allow_origins)? {
+        Some(cors) => Ok(outer_stack(cors.layer(service))),
+        None => Ok(outer_stack(service)),
+    }
+}
+
+fn outer_stack<S, B>(service: S) -> BoxService
+where
+    S: Service<Request<Body>, Response = Response<B>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Default + Send + 'static,
+    B::Error: Into<AnyError> + std::fmt::Display,
+{
+    let service = ServiceBuilder::new()
+        // Outside of CatchPanicLayer, so 500 of a panic is traced and has request id too.
+        .map_request(drop_invalid_request_id)
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(CatchPanicLayer::new())
+        .service(service)
+        .map_response(|resp| resp.map(|body| body.map_err(Into::into).boxed_unsync()));
+
+    BoxCloneService::new(service)
+}
+
+/// Id of request, set by [`stack`].
+pub fn request_id<B>(req: &Request<B>) -> Option<&str> {
+    req.headers().get(X_REQUEST_ID).and_then(|v| v.to_str().ok())
+}
+
+fn drop_invalid_request_id(mut req: Request<Body>) -> Request<Body> {
+    let valid = match req.headers().get(X_REQUEST_ID) {
+        Some(id) => is_valid_request_id(id.as_bytes()),
+        None => return req,
+    };
+
+    // Multiple values are ambiguous, start over with a fresh id.
+    if !valid || req.headers().get_all(X_REQUEST_ID).iter().count() > 1 {
+        req.headers_mut().remove(X_REQUEST_ID);
+    }
+
+    req
+}
+
+fn is_valid_request_id(id: &[u8]) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.iter().all(|&b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+// Same fields as default span of TraceLayer plus request id.
+// Every request starts a trace of its own unless "traceparent" continues one.
+fn request_span(req: &Request<Body>) -> Span {
+    let span = tracing::info_span!(
+        parent: None,
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        request_id = %request_id(req).unwrap_or_default(),
+    );
+
+    telemetry::set_parent(&span, req.headers());
+
+    span
+}
+
+fn cors_layer(origins: &[String]) -> Result<Option<CorsLayer>, AnyError> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        AllowOrigin::from(Any)
+    } else {
+        let origins = origins.iter()
+            .map(|o| HeaderValue::from_str(o))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| "Invalid origin in cors_allow_origins.")?;
+
+        AllowOrigin::list(origins)
+    };
+
+    let layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(Any)
+        .allow_headers(Any);
+
+    Ok(Some(layer))
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_concurrency_limit() -> usize {
+    1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::{Method, StatusCode};
+    use hyper::service::service_fn;
+
+    async fn call(req: Request<Body>) -> Response<BoxBody> {
+        call_with(req, &MiddlewareConfig::default()).await
+    }
+
+    async fn call_with(req: Request<Body>, config: &MiddlewareConfig) -> Response<BoxBody> {
+        let service = service_fn(|req: Request<Body>| async move {
+            if req.uri().path() == "/panic" {
+                panic!("handler panicked");
+            }
+
+            // Tells whether request got through to service.
+            let resp = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap();
+
+            Ok::<_, Infallible>(resp)
+        });
+
+        let service = stack(service, config).unwrap();
+
+        service.oneshot(req).await.unwrap()
+    }
+
+    fn request(path: &str, id: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri(path);
+
+        if let Some(id) = id {
+            builder = builder.header(X_REQUEST_ID, id);
+        }
+
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn panic_response_has_request_id() {
+        let resp = call(request("/panic", Some("abc-123"))).await;
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(resp.headers()[X_REQUEST_ID], "abc-123");
+
+        let resp = call(request("/panic", None)).await;
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(resp.headers().contains_key(X_REQUEST_ID));
+    }
+
+    #[tokio::test]
+    async fn invalid_request_id_is_replaced() {
+        let resp = call(request("/", Some("abc-123"))).await;
+
+        assert_eq!(resp.headers()[X_REQUEST_ID], "abc-123");
+
+        let resp = call(request("/", Some("has space"))).await;
+
+        assert_ne!(resp.headers()[X_REQUEST_ID], "has space");
+
+        let long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        let resp = call(request("/", Some(&long))).await;
+
+        assert_ne!(resp.headers()[X_REQUEST_ID], long.as_str());
+    }
+
+    #[tokio::test]
+    async fn options_reaches_service_without_cors() {
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .header("origin", "http://example.com")
+            .header("access-control-request-method", "POST")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = call(req).await;
+
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(!resp.headers().contains_key("access-control-allow-methods"));
+        assert!(!resp.headers().contains_key("vary"));
+    }
+
+    #[tokio::test]
+    async fn options_preflight_with_cors() {
+        let config = MiddlewareConfig {
+            cors_allow_origins: vec!["http://example.com".to_owned()],
+            ..MiddlewareConfig::default()
+        };
+
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .header("origin", "http://example.com")
+            .header("access-control-request-method", "POST")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = call_with(req, &config).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["access-control-allow-origin"], "http://example.com");
+    }
+
+    #[test]
+    fn zero_limits_are_rejected() {
+        let config = MiddlewareConfig { timeout_secs: 0, ..MiddlewareConfig::default() };
+
+        assert!(config.validate().is_err());
+
+        let config = MiddlewareConfig { concurrency_limit: 0, ..MiddlewareConfig::default() };
+
+        assert!(config.validate().is_err());
+        assert!(MiddlewareConfig::default().validate().is_ok());
+    }
+}
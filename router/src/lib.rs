@@ -0,0 +1,396 @@
+//! Small path and method router for hyper servers.
+
+mod pattern;
+
+use pattern::{split_path, Pattern};
+
+use std::convert::Infallible;
+use std::future::{ready, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::body::HttpBody;
+use hyper::header::{ALLOW, CONTENT_LENGTH};
+
+type BoxFuture = Pin<Box<dyn Future<Output = Response<Body>> + Send>>;
+type Handler = Arc<dyn Fn(Request<Body>) -> BoxFuture + Send + Sync>;
+
+/// Values captured by `:name` and `*name` segments.
+///
+/// Inserted into request extensions before handler is called.
+#[derive(Clone, Debug, Default)]
+pub struct Params {
+    list: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.list.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct Route {
+    // `None` matches every method.
+    method: Option<Method>,
+    pattern: Pattern,
+    handler: Handler,
+}
+
+enum Lookup {
+    Found(Handler, Vec<(String, String)>),
+    NotAllowed(Vec<Method>),
+    NotFound,
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    mounts: Vec<(Pattern, Router)>,
+    fallback: Option<Handler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<H, F>(mut self, method: Method, path: &str, handler: H) -> Self
+    where
+        H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method: Some(method),
+            pattern: Pattern::parse(path),
+            handler: boxed(handler),
+        });
+
+        self
+    }
+
+    /// Route every method on `path` to `handler`.
+    pub fn any<H, F>(mut self, path: &str, handler: H) -> Self
+    where
+        H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method: None,
+            pattern: Pattern::parse(path),
+            handler: boxed(handler),
+        });
+
+        self
+    }
+
+    pub fn get<H, F>(self, path: &str, handler: H) -> Self
+    where
+        H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.route(Method::GET, path, handler)
+    }
+
+    pub fn post<H, F>(self, path: &str, handler: H) -> Self
+    where
+        H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.route(Method::POST, path, handler)
+    }
+
+    pub fn put<H, F>(self, path: &str, handler: H) -> Self
+    where
+        H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.route(Method::PUT, path, handler)
+    }
+
+    pub fn delete<H, F>(self, path: &str, handler: H) -> Self
+    where
+        H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.route(Method::DELETE, path, handler)
+    }
+
+    /// Route requests under `prefix` to `router`. Prefix is not visible to its patterns.
+    pub fn mount(mut self, prefix: &str, router: Router) -> Self {
+        self.mounts.push((Pattern::parse(prefix), router));
+
+        self
+    }
+
+    /// Handler for requests that don't match any route, instead of "404 Not Found".
+    pub fn fallback<H, F>(mut self, handler: H) -> Self
+    where
+        H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.fallback = Some(boxed(handler));
+
+        self
+    }
+
+    /// Call matching handler. Returned future doesn't borrow router.
+    pub fn serve(
+        &self,
+        mut req: Request<Body>,
+    ) -> impl Future<Output = Result<Response<Body>, Infallible>> + Send + 'static {
+        let head = req.method() == Method::HEAD;
+
+        let path: Vec<&str> = split_path(req.uri().path()).collect();
+        let lookup = self.lookup(req.method(), &path);
+
+        let fut: BoxFuture = match lookup {
+            Lookup::Found(handler, list) => {
+                req.extensions_mut().insert(Params { list });
+
+                handler(req)
+            },
+            Lookup::NotAllowed(methods) => Box::pin(ready(method_not_allowed(methods))),
+            Lookup::NotFound => Box::pin(ready(not_found())),
+        };
+
+        async move {
+            let resp = fut.await;
+
+            Ok(if head { strip_body(resp) } else { resp })
+        }
+    }
+
+    fn lookup(&self, method: &Method, path: &[&str]) -> Lookup {
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            let mut params = Vec::new();
+
+            if !route.pattern.matches(path, &mut params) {
+                continue;
+            }
+
+            match &route.method {
+                Some(m) if m != method && !(m == Method::GET && method == Method::HEAD) => {
+                    allowed.push(m.clone());
+                },
+                _ => return Lookup::Found(route.handler.clone(), params),
+            }
+        }
+
+        for (prefix, router) in &self.mounts {
+            let mut params = Vec::new();
+
+            let matched = match prefix.match_prefix(path, &mut params) {
+                Some(matched) => matched,
+                None => continue,
+            };
+
+            match router.lookup(method, &path[matched..]) {
+                Lookup::Found(handler, inner) => {
+                    params.extend(inner);
+
+                    return Lookup::Found(handler, params);
+                },
+                Lookup::NotAllowed(methods) => allowed.extend(methods),
+                Lookup::NotFound => (),
+            }
+        }
+
+        if !allowed.is_empty() {
+            return Lookup::NotAllowed(allowed);
+        }
+
+        match &self.fallback {
+            Some(handler) => Lookup::Found(handler.clone(), Vec::new()),
+            None => Lookup::NotFound,
+        }
+    }
+}
+
+fn boxed<H, F>(handler: H) -> Handler
+where
+    H: Fn(Request<Body>) -> F + Send + Sync + 'static,
+    F: Future<Output = Response<Body>> + Send + 'static,
+{
+    Arc::new(move |req| Box::pin(handler(req)) as BoxFuture)
+}
+
+// Responses to HEAD requests routed to GET handlers must not have a body.
+fn strip_body(resp: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = resp.into_parts();
+
+    if let Some(len) = body.size_hint().exact() {
+        parts.headers.entry(CONTENT_LENGTH).or_insert_with(|| len.into());
+    }
+
+    Response::from_parts(parts, Body::empty())
+}
+
+fn method_not_allowed(mut methods: Vec<Method>) -> Response<Body> {
+    if methods.contains(&Method::GET) {
+        methods.push(Method::HEAD);
+    }
+
+    let mut allow: Vec<&str> = Vec::new();
+
+    for method in &methods {
+        if !allow.contains(&method.as_str()) {
+            allow.push(method.as_str());
+        }
+    }
+
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(ALLOW, allow.join(", "))
+        .header("content-type", "text/plain")
+        .body(Body::from("Method Not Allowed"))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header("content-type", "text/plain")
+        .body(Body::from("Not Found"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn call(router: &Router, method: Method, path: &str) -> Response<Body> {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+
+        router.serve(req).await.unwrap()
+    }
+
+    async fn body(resp: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    // Handler answering with its name and captured params.
+    fn echo(name: &'static str) -> impl Fn(Request<Body>) -> std::future::Ready<Response<Body>> + Send + Sync {
+        move |req| {
+            let params = req.extensions().get::<Params>().unwrap();
+
+            let list: Vec<String> = params.list.iter()
+                .map(|(n, v)| format!("{}={}", n, v))
+                .collect();
+
+            ready(Response::new(Body::from(format!("{} {}", name, list.join(",")))))
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_by_method_and_path() {
+        let router = Router::new()
+            .get("/users/:id", echo("get"))
+            .delete("/users/:id", echo("delete"))
+            .get("/files/*path", echo("files"));
+
+        assert_eq!(body(call(&router, Method::GET, "/users/7").await).await, "get id=7");
+        assert_eq!(body(call(&router, Method::DELETE, "/users/7").await).await, "delete id=7");
+        assert_eq!(body(call(&router, Method::GET, "/files/a/b").await).await, "files path=a/b");
+    }
+
+    #[tokio::test]
+    async fn first_matching_route_wins() {
+        let router = Router::new()
+            .get("/users/me", echo("me"))
+            .get("/users/:id", echo("id"));
+
+        assert_eq!(body(call(&router, Method::GET, "/users/me").await).await, "me ");
+        assert_eq!(body(call(&router, Method::GET, "/users/7").await).await, "id id=7");
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let router = Router::new().get("/a", echo("a"));
+
+        let resp = call(&router, Method::GET, "/b").await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn method_not_allowed_lists_allowed_methods() {
+        let router = Router::new()
+            .get("/a", echo("get"))
+            .post("/a", echo("post"))
+            .put("/b", echo("put"));
+
+        let resp = call(&router, Method::DELETE, "/a").await;
+
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[ALLOW], "GET, POST, HEAD");
+
+        let resp = call(&router, Method::GET, "/b").await;
+
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[ALLOW], "PUT");
+    }
+
+    #[tokio::test]
+    async fn any_matches_every_method() {
+        let router = Router::new().any("/a", echo("any"));
+
+        for method in [Method::GET, Method::POST, Method::PATCH] {
+            assert_eq!(call(&router, method, "/a").await.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn head_uses_get_without_body() {
+        let router = Router::new().get("/a", |_| ready(Response::new(Body::from("hello"))));
+
+        let resp = call(&router, Method::HEAD, "/a").await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_LENGTH], "5");
+        assert_eq!(body(resp).await, "");
+    }
+
+    #[tokio::test]
+    async fn mounts_strip_prefix_and_keep_params() {
+        let api = Router::new()
+            .get("/users/:id", echo("user"))
+            .post("/users", echo("create"));
+
+        let router = Router::new()
+            .get("/", echo("root"))
+            .mount("/api/:version", api);
+
+        assert_eq!(body(call(&router, Method::GET, "/api/v1/users/7").await).await, "user version=v1,id=7");
+        assert_eq!(body(call(&router, Method::GET, "/").await).await, "root ");
+
+        let resp = call(&router, Method::GET, "/api/v1/users").await;
+
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[ALLOW], "POST");
+
+        assert_eq!(call(&router, Method::GET, "/api/v1/other").await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn fallback_replaces_not_found() {
+        let router = Router::new()
+            .get("/a", echo("a"))
+            .fallback(echo("fallback"));
+
+        assert_eq!(body(call(&router, Method::GET, "/b").await).await, "fallback ");
+
+        // Method mismatch is still 405.
+        assert_eq!(call(&router, Method::POST, "/a").await.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,148 @@
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+/// Parsed route path like "/users/:id/*rest".
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    pub fn parse(path: &str) -> Self {
+        let parts: Vec<&str> = split_path(path).collect();
+        let last = parts.len().saturating_sub(1);
+
+        let segments = parts.iter()
+            .enumerate()
+            .map(|(i, part)| {
+                if let Some(name) = part.strip_prefix(':') {
+                    Segment::Param(name.to_owned())
+                } else if let Some(name) = part.strip_prefix('*') {
+                    assert!(i == last, "wildcard must be last segment in {:?}", path);
+
+                    Segment::Rest(name.to_owned())
+                } else {
+                    Segment::Literal((*part).to_owned())
+                }
+            })
+            .collect();
+
+        Self { segments }
+    }
+
+    /// Match whole path.
+    pub fn matches(&self, path: &[&str], params: &mut Vec<(String, String)>) -> bool {
+        self.match_prefix(path, params) == Some(path.len())
+    }
+
+    /// Match start of path. Returns number of matched path segments.
+    pub fn match_prefix(
+        &self,
+        path: &[&str],
+        params: &mut Vec<(String, String)>,
+    ) -> Option<usize> {
+        let mut captured = Vec::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Rest(name) => {
+                    captured.push((name.clone(), path[i..].join("/")));
+
+                    params.extend(captured);
+
+                    return Some(path.len());
+                },
+                Segment::Literal(s) => {
+                    if path.get(i) != Some(&s.as_str()) {
+                        return None;
+                    }
+                },
+                Segment::Param(name) => match path.get(i) {
+                    Some(value) => captured.push((name.clone(), (*value).to_owned())),
+                    None => return None,
+                },
+            }
+        }
+
+        params.extend(captured);
+
+        Some(self.segments.len())
+    }
+}
+
+/// Split path into non-empty segments.
+pub fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        let path: Vec<&str> = split_path(path).collect();
+        let mut params = Vec::new();
+
+        Pattern::parse(pattern).matches(&path, &mut params).then_some(params)
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(capture("/users/list", "/users/list"), Some(vec![]));
+        assert_eq!(capture("/users/list", "/users/list/"), Some(vec![]));
+        assert_eq!(capture("/users/list", "/users"), None);
+        assert_eq!(capture("/users/list", "/users/list/more"), None);
+        assert_eq!(capture("/", "/"), Some(vec![]));
+        assert_eq!(capture("/", "/a"), None);
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(
+            capture("/users/:id/posts/:post", "/users/7/posts/42"),
+            Some(vec![pair("id", "7"), pair("post", "42")]),
+        );
+        assert_eq!(capture("/users/:id", "/users"), None);
+        assert_eq!(capture("/users/:id", "/users/7/posts"), None);
+    }
+
+    #[test]
+    fn rest() {
+        assert_eq!(capture("/files/*path", "/files/a/b/c.txt"), Some(vec![pair("path", "a/b/c.txt")]));
+        assert_eq!(capture("/files/*path", "/files"), Some(vec![pair("path", "")]));
+        assert_eq!(capture("/:dir/*path", "/d/x"), Some(vec![pair("dir", "d"), pair("path", "x")]));
+        assert_eq!(capture("/files/*path", "/other/a"), None);
+    }
+
+    #[test]
+    fn failed_match_keeps_params() {
+        let path: Vec<&str> = split_path("/users/7").collect();
+        let mut params = vec![pair("kept", "1")];
+
+        assert!(!Pattern::parse("/posts/:id").matches(&path, &mut params));
+        assert!(!Pattern::parse("/:a/:b/:c").matches(&path, &mut params));
+        assert_eq!(params, vec![pair("kept", "1")]);
+    }
+
+    #[test]
+    fn prefix() {
+        let path: Vec<&str> = split_path("/api/v1/users").collect();
+        let mut params = Vec::new();
+
+        assert_eq!(Pattern::parse("/api/:version").match_prefix(&path, &mut params), Some(2));
+        assert_eq!(params, vec![pair("version", "v1")]);
+        assert_eq!(Pattern::parse("/other").match_prefix(&path, &mut params), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "wildcard must be last segment")]
+    fn rest_not_last() {
+        Pattern::parse("/*path/more");
+    }
+}
@@ -22,10 +22,7 @@ async fn main() -> Result<(), AnyError> {
     let stream = connector.connect(domain, stream).await?;
 
     // Get negotiated alpn protocol.
-    let h2_only = match stream.get_ref().1.get_alpn_protocol() {
-        Some(b"h2") => true,
-        _ => false,
-    };
+    let h2_only = matches!(stream.get_ref().1.get_alpn_protocol(), Some(b"h2"));
 
     // Switch to http2 mode if negotiated alpn is http2.
     let (mut sender, connection) = conn::Builder::new()
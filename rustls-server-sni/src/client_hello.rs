@@ -0,0 +1,738 @@
+//! TLS ClientHello read before handshake: server name and JA3/JA4 fingerprints.
+//!
+//! `ClientHello` given to certificate resolvers has no cipher suites or extensions,
+//! so the record is read before rustls gets the connection and replayed afterwards.
+
+use crate::AnyError;
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+// ClientHello is refused if it doesn't fit, real ones are a few hundred bytes.
+const MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+const SERVER_NAME_HOST: u8 = 0;
+
+// Fatal "unrecognized_name" alert record.
+// SEE: https://datatracker.ietf.org/doc/html/rfc6066#section-3
+const ALERT_UNRECOGNIZED_NAME: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 112];
+
+/// What is known about a client before handshake.
+pub struct Hello {
+    pub server_name: Option<String>,
+    pub fingerprint: Fingerprint,
+}
+
+/// Fingerprint of a connection. Inserted into request extensions.
+#[derive(Clone, Debug)]
+pub struct Fingerprint {
+    /// Md5 of JA3 string, the form JA3 is usually shared in.
+    pub ja3_hash: String,
+    pub ja4: String,
+}
+
+/// Fingerprints listed in `deny` are rejected. When `allow` isn't empty, only listed
+/// fingerprints are accepted. Entries are JA3 hashes or JA4 strings.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct FingerprintRules {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl FingerprintRules {
+    pub fn allows(&self, fp: &Fingerprint) -> bool {
+        let matches = |list: &[String]| list.iter().any(|f| *f == fp.ja3_hash || *f == fp.ja4);
+
+        if matches(&self.deny) {
+            return false;
+        }
+
+        self.allow.is_empty() || matches(&self.allow)
+    }
+}
+
+/// Read ClientHello from `io`.
+///
+/// Returned stream yields read bytes again, so it can be given to a TLS acceptor.
+pub async fn read<IO>(mut io: IO) -> Result<(Rewind<IO>, Hello), AnyError>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut raw = Vec::new();
+    let mut handshake = Vec::new();
+
+    // Handshake message can be split across records.
+    loop {
+        let mut header = [0; 5];
+        io.read_exact(&mut header).await?;
+
+        if header[0] != RECORD_HANDSHAKE {
+            return Err("connection doesn't start with a TLS handshake".into());
+        }
+
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+
+        if raw.len() + 5 + len > MAX_CLIENT_HELLO {
+            return Err("ClientHello is too big".into());
+        }
+
+        let mut fragment = vec![0; len];
+        io.read_exact(&mut fragment).await?;
+
+        raw.extend(&header);
+        raw.extend(&fragment);
+        handshake.extend(&fragment);
+
+        if handshake.len() >= 4 {
+            let body_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+
+            if handshake.len() >= 4 + body_len {
+                break;
+            }
+        }
+    }
+
+    if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+        return Err("first handshake message isn't ClientHello".into());
+    }
+
+    let hello = ClientHello::parse(&handshake[4..]).ok_or("malformed ClientHello")?;
+
+    let info = Hello {
+        fingerprint: hello.fingerprint(),
+        server_name: hello.server_name,
+    };
+
+    let rewind = Rewind {
+        prefix: raw,
+        pos: 0,
+        io,
+    };
+
+    Ok((rewind, info))
+}
+
+/// Refuse handshake because no certificate is served for requested name.
+pub async fn reject_unrecognized_name<IO: AsyncWrite + Unpin>(io: &mut IO) {
+    let _ = io.write_all(&ALERT_UNRECOGNIZED_NAME).await;
+    let _ = io.shutdown().await;
+}
+
+#[derive(Default)]
+struct ClientHello {
+    version: u16,
+    ciphers: Vec<u16>,
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    sig_algs: Vec<u16>,
+    versions: Vec<u16>,
+    alpn: Option<Vec<u8>>,
+    server_name: Option<String>,
+}
+
+impl ClientHello {
+    // SEE: https://datatracker.ietf.org/doc/html/rfc8446#section-4.1.2
+    fn parse(body: &[u8]) -> Option<Self> {
+        let mut r = Reader(body);
+        let mut hello = Self {
+            version: r.u16()?,
+            ..Self::default()
+        };
+
+        r.take(32)?;
+        let session_id_len = r.u8()? as usize;
+        r.take(session_id_len)?;
+
+        let mut ciphers = Reader(r.vec16()?);
+        while !ciphers.0.is_empty() {
+            hello.ciphers.push(ciphers.u16()?);
+        }
+
+        let compression_len = r.u8()? as usize;
+        r.take(compression_len)?;
+
+        // Extensions are optional in old hellos.
+        if r.0.is_empty() {
+            return Some(hello);
+        }
+
+        let mut exts = Reader(r.vec16()?);
+
+        while !exts.0.is_empty() {
+            let kind = exts.u16()?;
+            let mut data = Reader(exts.vec16()?);
+
+            hello.extensions.push(kind);
+
+            match kind {
+                // SEE: https://datatracker.ietf.org/doc/html/rfc6066#section-3
+                EXT_SERVER_NAME => {
+                    let mut list = Reader(data.vec16()?);
+
+                    while !list.0.is_empty() {
+                        let name_type = list.u8()?;
+                        let name = list.vec16()?;
+
+                        if name_type == SERVER_NAME_HOST {
+                            hello.server_name = Some(String::from_utf8(name.to_vec()).ok()?);
+                            break;
+                        }
+                    }
+                },
+                EXT_SUPPORTED_GROUPS => hello.groups = data.list16()?,
+                EXT_SIGNATURE_ALGORITHMS => hello.sig_algs = data.list16()?,
+                EXT_EC_POINT_FORMATS => {
+                    let len = data.u8()? as usize;
+                    hello.point_formats = data.take(len)?.to_vec();
+                },
+                EXT_SUPPORTED_VERSIONS => {
+                    let len = data.u8()? as usize;
+                    let mut versions = Reader(data.take(len)?);
+
+                    while !versions.0.is_empty() {
+                        hello.versions.push(versions.u16()?);
+                    }
+                },
+                EXT_ALPN => {
+                    let mut list = Reader(data.vec16()?);
+                    let len = list.u8()? as usize;
+
+                    hello.alpn = Some(list.take(len)?.to_vec());
+                },
+                _ => (),
+            }
+        }
+
+        Some(hello)
+    }
+
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint {
+            ja3_hash: hex(&Md5::digest(self.ja3().as_bytes())),
+            ja4: self.ja4(),
+        }
+    }
+
+    // SEE: https://github.com/salesforce/ja3
+    fn ja3(&self) -> String {
+        fn join<T: ToString>(values: impl Iterator<Item = T>) -> String {
+            values.map(|v| v.to_string()).collect::<Vec<_>>().join("-")
+        }
+
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(not_grease(&self.ciphers)),
+            join(not_grease(&self.extensions)),
+            join(not_grease(&self.groups)),
+            join(self.point_formats.iter()),
+        )
+    }
+
+    // SEE: https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md
+    fn ja4(&self) -> String {
+        let version = not_grease(&self.versions).max().unwrap_or(self.version);
+
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            _ => "00",
+        };
+
+        let sni = match self.extensions.contains(&EXT_SERVER_NAME) {
+            true => 'd',
+            false => 'i',
+        };
+
+        let ciphers: Vec<u16> = not_grease(&self.ciphers).collect();
+        let extensions: Vec<u16> = not_grease(&self.extensions).collect();
+
+        let mut sorted_ciphers = ciphers.clone();
+        sorted_ciphers.sort_unstable();
+
+        let mut sorted_extensions: Vec<u16> = extensions.iter()
+            .copied()
+            .filter(|&e| e != EXT_SERVER_NAME && e != EXT_ALPN)
+            .collect();
+        sorted_extensions.sort_unstable();
+
+        let mut ext_part = hex_list(&sorted_extensions);
+
+        if !self.sig_algs.is_empty() {
+            ext_part.push('_');
+            ext_part.push_str(&hex_list(&self.sig_algs));
+        }
+
+        format!(
+            "t{}{}{:02}{:02}{}_{}_{}",
+            version,
+            sni,
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn_chars(self.alpn.as_deref()),
+            truncated_hash(&sorted_ciphers, &hex_list(&sorted_ciphers)),
+            truncated_hash(&sorted_extensions, &ext_part),
+        )
+    }
+}
+
+// GREASE values are random, they would make every fingerprint different.
+// SEE: https://datatracker.ietf.org/doc/html/rfc8701
+fn not_grease(values: &[u16]) -> impl Iterator<Item = u16> + '_ {
+    values.iter()
+        .copied()
+        .filter(|v| v & 0x0f0f != 0x0a0a || v >> 8 != v & 0xff)
+}
+
+fn alpn_chars(alpn: Option<&[u8]>) -> String {
+    let (first, last) = match alpn {
+        Some(&[first, .., last]) => (first, last),
+        Some(&[only]) => (only, only),
+        _ => return "00".to_owned(),
+    };
+
+    if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+        format!("{}{}", first as char, last as char)
+    } else {
+        let first = hex(&[first]);
+        let last = hex(&[last]);
+
+        format!("{}{}", &first[..1], &last[1..])
+    }
+}
+
+fn hex_list(values: &[u16]) -> String {
+    values.iter()
+        .map(|v| format!("{:04x}", v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn truncated_hash(values: &[u16], s: &str) -> String {
+    match values.is_empty() {
+        true => "000000000000".to_owned(),
+        false => hex(&Sha256::digest(s.as_bytes()))[..12].to_owned(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+
+        self.take(len)
+    }
+
+    fn list16(&mut self) -> Option<Vec<u16>> {
+        let mut list = Reader(self.vec16()?);
+        let mut values = Vec::new();
+
+        while !list.0.is_empty() {
+            values.push(list.u16()?);
+        }
+
+        Some(values)
+    }
+}
+
+/// Stream that yields already read bytes before reading from `io`.
+pub struct Rewind<IO> {
+    prefix: Vec<u8>,
+    pos: usize,
+    io: IO,
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Rewind<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let len = (self.prefix.len() - self.pos).min(buf.remaining());
+            let start = self.pos;
+
+            buf.put_slice(&self.prefix[start..start + len]);
+            self.pos += len;
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Rewind<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXT_RENEGOTIATION_INFO: u16 = 0xff01;
+
+    // Sent by curl 7.88.1 with OpenSSL 3.0 for "curl --http2 https://localtest:4499/".
+    const CURL_HELLO: &str = "\
+        1603010200010001fc0303957fc726b6dd43b5e4d4ada0cd0b1c76b6890466bc44a33ce9da418df67c5670207e9ed8a2\
+        3f94f403c95554a74ae6c1da9336523559167b2abe8ec4814a33ff58003e130213031301c02cc030009fcca9cca8ccaa\
+        c02bc02f009ec024c028006bc023c0270067c00ac0140039c009c0130033009d009c003d003c0035002f00ff01000175\
+        0000000e000c0000096c6f63616c74657374000b000403000102000a00160014001d0017001e00190018010001010102\
+        010301040010000e000c02683208687474702f312e31001600000017000000310000000d002a00280403050306030807\
+        08080809080a080b080408050806040105010601030303010302040205020602002b0009080304030303020301002d00\
+        020101003300260024001d0020fc7d4e73f75491e7c74f5f71d1ae056edeb356865ebf26a6eaa794efd94a2a34001500\
+        b40000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
+        000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
+        000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
+        00000000000000000000000000000000000000000000000000000000000000000000000000";
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend(data);
+        out
+    }
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    fn ext(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_be_bytes().to_vec();
+        out.extend(vec16(data));
+        out
+    }
+
+    fn sni(name: &str) -> Vec<u8> {
+        let mut entry = vec![SERVER_NAME_HOST];
+        entry.extend(vec16(name.as_bytes()));
+
+        ext(EXT_SERVER_NAME, &vec16(&entry))
+    }
+
+    fn alpn(protocols: &[&[u8]]) -> Vec<u8> {
+        let mut list = Vec::new();
+
+        for protocol in protocols {
+            list.push(protocol.len() as u8);
+            list.extend(*protocol);
+        }
+
+        ext(EXT_ALPN, &vec16(&list))
+    }
+
+    fn groups(values: &[u16]) -> Vec<u8> {
+        ext(EXT_SUPPORTED_GROUPS, &vec16(&u16s(values)))
+    }
+
+    fn sig_algs(values: &[u16]) -> Vec<u8> {
+        ext(EXT_SIGNATURE_ALGORITHMS, &vec16(&u16s(values)))
+    }
+
+    fn point_formats(values: &[u8]) -> Vec<u8> {
+        let mut data = vec![values.len() as u8];
+        data.extend(values);
+
+        ext(EXT_EC_POINT_FORMATS, &data)
+    }
+
+    fn versions(values: &[u16]) -> Vec<u8> {
+        let mut data = vec![(values.len() * 2) as u8];
+        data.extend(u16s(values));
+
+        ext(EXT_SUPPORTED_VERSIONS, &data)
+    }
+
+    // ClientHello record, `extensions` block is left out when it is `None`.
+    fn record(version: u16, ciphers: &[u16], extensions: Option<&[Vec<u8>]>) -> Vec<u8> {
+        let mut body = version.to_be_bytes().to_vec();
+        body.extend([0; 32]);
+        body.push(0);
+        body.extend(vec16(&u16s(ciphers)));
+        body.extend([1, 0]);
+
+        if let Some(extensions) = extensions {
+            body.extend(vec16(&extensions.concat()));
+        }
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![RECORD_HANDSHAKE, 3, 1];
+        record.extend(vec16(&handshake));
+        record
+    }
+
+    async fn hello(bytes: &[u8]) -> Hello {
+        read(bytes).await.unwrap().1
+    }
+
+    // SEE: https://github.com/salesforce/ja3#how-it-works
+    #[tokio::test]
+    async fn ja3_reference() {
+        let ciphers = [47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4];
+        let extensions = [sni("example.com"), groups(&[23, 24, 25]), point_formats(&[0])];
+
+        let bytes = record(0x0301, &ciphers, Some(&extensions));
+        let body = &bytes[9..];
+
+        assert_eq!(
+            ClientHello::parse(body).unwrap().ja3(),
+            "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0",
+        );
+
+        let hello = hello(&bytes).await;
+
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.fingerprint.ja3_hash, "ada70206e40642a3e4461f35503241d5");
+        assert_eq!(hello.fingerprint.ja4, "t10d120300_d94e65cdb899_33a13ba74d1c");
+    }
+
+    // Chrome hello of JA4 documentation, with GREASE values added like Chrome sends them.
+    // SEE: https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md
+    #[tokio::test]
+    async fn ja4_reference_with_grease() {
+        let ciphers = [
+            0x2a2a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8,
+            0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+        ];
+
+        let extensions = [
+            ext(0x0a0a, &[]),
+            sni("example.com"),
+            ext(0x0017, &[]),
+            ext(EXT_RENEGOTIATION_INFO, &[0]),
+            groups(&[0x4a4a, 0x001d, 0x0017, 0x0018]),
+            point_formats(&[0]),
+            ext(0x0023, &[]),
+            alpn(&[b"h2", b"http/1.1"]),
+            ext(0x0005, &[1, 0, 0, 0, 0]),
+            sig_algs(&[0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601]),
+            ext(0x0012, &[]),
+            ext(0x0033, &vec16(&[])),
+            ext(0x002d, &[1, 1]),
+            versions(&[0x6a6a, 0x0304, 0x0303]),
+            ext(0x001b, &[2, 0, 2]),
+            ext(0x4469, &[0, 3, 2, b'h', b'2']),
+            ext(0x0015, &[0; 16]),
+            ext(0x3a3a, &[0]),
+        ];
+
+        let bytes = record(0x0303, &ciphers, Some(&extensions));
+
+        assert_eq!(
+            ClientHello::parse(&bytes[9..]).unwrap().ja3(),
+            "771,4865-4866-4867-49195-49199-49196-49200-52393-52392-49171-49172-156-157-47-53,\
+             0-23-65281-10-11-35-16-5-13-18-51-45-43-27-17513-21,29-23-24,0",
+        );
+
+        let hello = hello(&bytes).await;
+
+        assert_eq!(hello.fingerprint.ja3_hash, "cd08e31494f9531f560d64c695473da9");
+        assert_eq!(hello.fingerprint.ja4, "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[tokio::test]
+    async fn captured_hello() {
+        let bytes = unhex(CURL_HELLO);
+
+        let (mut rewind, hello) = read(&bytes[..]).await.unwrap();
+
+        assert_eq!(hello.server_name.as_deref(), Some("localtest"));
+        assert_eq!(hello.fingerprint.ja3_hash, "0149f47eabf9a20d0893e2a44e5a6323");
+        assert_eq!(hello.fingerprint.ja4, "t13d3112h2_e8f1e7e78f70_b26ce05bbdd6");
+
+        // Acceptor gets every byte that was read.
+        let mut replayed = Vec::new();
+        rewind.read_to_end(&mut replayed).await.unwrap();
+
+        assert_eq!(replayed, bytes);
+    }
+
+    #[tokio::test]
+    async fn hello_split_across_records() {
+        let bytes = unhex(CURL_HELLO);
+        let (header, handshake) = bytes.split_at(5);
+        let (first, second) = handshake.split_at(100);
+
+        let mut split = Vec::new();
+
+        for fragment in [first, second] {
+            split.extend(&header[..3]);
+            split.extend(vec16(fragment));
+        }
+
+        let (mut rewind, hello) = read(&split[..]).await.unwrap();
+
+        assert_eq!(hello.server_name.as_deref(), Some("localtest"));
+        assert_eq!(hello.fingerprint.ja4, "t13d3112h2_e8f1e7e78f70_b26ce05bbdd6");
+
+        let mut replayed = Vec::new();
+        rewind.read_to_end(&mut replayed).await.unwrap();
+
+        assert_eq!(replayed, split);
+    }
+
+    #[tokio::test]
+    async fn without_sni_and_alpn() {
+        let extensions = [groups(&[0x001d]), versions(&[0x0304])];
+
+        let hello = hello(&record(0x0303, &[0x1301], Some(&extensions))).await;
+
+        assert_eq!(hello.server_name, None);
+        assert!(hello.fingerprint.ja4.starts_with("t13i010200_"), "{}", hello.fingerprint.ja4);
+    }
+
+    #[tokio::test]
+    async fn sni_without_host_name() {
+        // Only name type 0 is a host name, extension still counts as SNI.
+        let mut entry = vec![1];
+        entry.extend(vec16(b"not a host"));
+
+        let extensions = [ext(EXT_SERVER_NAME, &vec16(&entry))];
+
+        let hello = hello(&record(0x0303, &[0x1301], Some(&extensions))).await;
+
+        assert_eq!(hello.server_name, None);
+        assert!(hello.fingerprint.ja4.starts_with("t12d010100_"), "{}", hello.fingerprint.ja4);
+    }
+
+    #[tokio::test]
+    async fn without_extensions() {
+        let hello = hello(&record(0x0303, &[0x002f], None)).await;
+
+        assert_eq!(hello.fingerprint.ja4, "t12i010000_ba72b8082249_000000000000");
+    }
+
+    #[tokio::test]
+    async fn only_first_alpn_is_used() {
+        let extensions = [alpn(&[b"http/1.1", b"h2"])];
+
+        let hello = hello(&record(0x0303, &[0x1301], Some(&extensions))).await;
+
+        assert!(hello.fingerprint.ja4.starts_with("t12i0101h1_"), "{}", hello.fingerprint.ja4);
+    }
+
+    #[test]
+    fn alpn_chars_edge_cases() {
+        assert_eq!(alpn_chars(None), "00");
+        assert_eq!(alpn_chars(Some(b"")), "00");
+        assert_eq!(alpn_chars(Some(b"h2")), "h2");
+        assert_eq!(alpn_chars(Some(b"h")), "hh");
+        assert_eq!(alpn_chars(Some(b"http/1.1")), "h1");
+        // Non alphanumeric ends use first and last hex digit.
+        assert_eq!(alpn_chars(Some(&[0xab, 0xcd])), "ad");
+        assert_eq!(alpn_chars(Some(b"h2-")), "6d");
+    }
+
+    #[test]
+    fn grease_values() {
+        let values = [0x0a0a, 0x1a1a, 0xfafa, 0x0a1a, 0x1a0a, 0x0303, 0x0000];
+
+        assert_eq!(not_grease(&values).collect::<Vec<_>>(), [0x0a1a, 0x1a0a, 0x0303, 0x0000]);
+    }
+
+    #[tokio::test]
+    async fn not_a_handshake() {
+        let http = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        assert!(read(&http[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_hello() {
+        let mut bytes = unhex(CURL_HELLO);
+
+        // Length of extensions block, after compression methods, says it is longer than the message.
+        bytes[9 + 2 + 32 + 1 + 32 + 2 + 62 + 2] = 0xff;
+
+        assert!(read(&bytes[..]).await.is_err());
+    }
+
+    #[test]
+    fn rules() {
+        let fp = Fingerprint {
+            ja3_hash: "ada70206e40642a3e4461f35503241d5".to_owned(),
+            ja4: "t13d1516h2_8daaf6152771_e5627efa2ab1".to_owned(),
+        };
+
+        let rules = |allow: &[&str], deny: &[&str]| FingerprintRules {
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            deny: deny.iter().map(|s| s.to_string()).collect(),
+        };
+
+        assert!(rules(&[], &[]).allows(&fp));
+        assert!(rules(&[&fp.ja4], &[]).allows(&fp));
+        assert!(!rules(&["other"], &[]).allows(&fp));
+        assert!(!rules(&[], &[&fp.ja3_hash]).allows(&fp));
+        assert!(!rules(&[&fp.ja4], &[&fp.ja3_hash]).allows(&fp));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::policy::{Policies, TlsPolicy};
+use crate::reload::State;
+use crate::site;
+use crate::util::{self, names};
+
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::CONTENT_TYPE;
+
+use rustls::sign::CertifiedKey;
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct CoalescingConfig {
+    // Names or wildcards that can be requested on a connection for
+    // another name, when certificate of that connection covers them.
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// Hosts requests of one connection are allowed to name.
+///
+/// SEE: https://datatracker.ietf.org/doc/html/rfc9110#section-15.5.20
+pub struct HostCheck {
+    // SNI of connection, or name of default domain when client sent none.
+    name: Option<String>,
+    // Names that are both allowed and covered by certificate.
+    coalesced: Vec<String>,
+    // Policy connection was accepted with.
+    policy: TlsPolicy,
+}
+
+impl HostCheck {
+    /// Check of a connection that sent `sni`, against `key` it was served.
+    ///
+    /// Without a key, like on a resumed session, a name counts as covered
+    /// only when every certificate of the domain covers it.
+    pub fn new(state: &State, sni: Option<&str>, key: Option<&CertifiedKey>) -> Self {
+        let covered: Vec<Vec<String>> = match key {
+            Some(key) => vec![util::certificate_names(key)],
+            None => state.resolver.keys(sni)
+                .unwrap_or_default()
+                .iter()
+                .map(util::certificate_names)
+                .collect(),
+        };
+
+        let coalesced = state.coalescing.allow.iter()
+            .filter(|allowed| {
+                !covered.is_empty()
+                    && covered.iter().all(|names| names.iter().any(|name| names::matches(name, allowed)))
+            })
+            .cloned()
+            .collect();
+
+        Self {
+            name: sni.map(str::to_owned).or_else(|| state.resolver.default_name()),
+            coalesced,
+            policy: state.policies.policy(sni).clone(),
+        }
+    }
+
+    /// Whether `req` can be served on this connection, `policies` of its state.
+    ///
+    /// Requests without a Host aren't checked. A coalesced name whose
+    /// TLS policy differs, like one requiring client certificates, isn't
+    /// allowed, since this connection's handshake didn't enforce it.
+    pub fn allows(&self, req: &Request<Body>, policies: &Policies) -> bool {
+        let (name, host) = match (&self.name, site::host(req)) {
+            (Some(name), Some(host)) => (name, host),
+            _ => return true,
+        };
+
+        if names::matches(name, &host) {
+            return true;
+        }
+
+        self.coalesced.iter().any(|allowed| names::matches(allowed, &host))
+            && policies.policy(Some(&host)) == &self.policy
+    }
+}
+
+pub fn misdirected() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::MISDIRECTED_REQUEST)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from("Misdirected Request"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::reload;
+
+    use serde_json::json;
+
+    fn state(localtest: serde_json::Value, allow: &[&str]) -> State {
+        let mut localtest = localtest;
+
+        localtest["name"] = json!("localtest");
+        localtest["key_location"] = json!("certs/key.pem");
+        localtest["cert_location"] = json!("certs/cert.pem");
+
+        reload::test_state(json!({
+            "domains": [
+                {
+                    "name": "localhost",
+                    "key_location": "certs/key.pem",
+                    "cert_location": "certs/cert.pem",
+                    "default": true,
+                },
+                localtest,
+            ],
+            "coalescing": { "allow": allow },
+        }))
+    }
+
+    // Check of a connection served certificate of `sni`, it covers localhost and localtest.
+    fn check(state: &State, sni: Option<&str>) -> HostCheck {
+        let key = state.resolver.keys(sni).unwrap().remove(0);
+
+        HostCheck::new(state, sni, Some(&key))
+    }
+
+    fn allows(state: &State, check: &HostCheck, host: Option<&str>) -> bool {
+        let mut builder = Request::builder().uri("/");
+
+        if let Some(host) = host {
+            builder = builder.header("host", host);
+        }
+
+        check.allows(&builder.body(Body::empty()).unwrap(), &state.policies)
+    }
+
+    #[test]
+    fn same_name() {
+        let state = state(json!({}), &[]);
+        let check = check(&state, Some("localhost"));
+
+        assert!(allows(&state, &check, Some("localhost")));
+        assert!(allows(&state, &check, Some("LOCALHOST.:443")));
+        assert!(!allows(&state, &check, Some("localtest")));
+    }
+
+    #[test]
+    fn coalesced_names() {
+        let state = state(json!({}), &["localtest", "example.com"]);
+        let check = check(&state, Some("localhost"));
+
+        assert!(allows(&state, &check, Some("localtest")));
+        // Allowed, but not covered by certificate.
+        assert!(!allows(&state, &check, Some("example.com")));
+    }
+
+    // Connections without SNI are checked against default domain.
+    #[test]
+    fn default_name() {
+        let state = state(json!({}), &[]);
+        let check = check(&state, None);
+
+        assert!(allows(&state, &check, Some("localhost")));
+        assert!(!allows(&state, &check, Some("localtest")));
+    }
+
+    #[test]
+    fn default_wildcard_name() {
+        let state = reload::test_state(json!({
+            "domains": [{
+                "name": "*.example.com",
+                "key_location": "certs/key.pem",
+                "cert_location": "certs/cert.pem",
+                "default": true,
+            }],
+        }));
+
+        let check = check(&state, None);
+
+        assert!(allows(&state, &check, Some("www.example.com")));
+        assert!(!allows(&state, &check, Some("example.com")));
+        assert!(!allows(&state, &check, Some("a.b.example.com")));
+    }
+
+    #[test]
+    fn no_host() {
+        let state = state(json!({}), &[]);
+
+        assert!(allows(&state, &check(&state, Some("localhost")), None));
+    }
+
+    // Handshake of localhost didn't ask for client certificate localtest requires.
+    #[test]
+    fn other_policy_not_coalesced() {
+        let client_auth = json!({ "tls": { "client_auth": { "ca_location": "certs/cert.pem" } } });
+        let state = state(client_auth, &["localtest", "localhost"]);
+
+        let check_localhost = check(&state, Some("localhost"));
+
+        assert!(!allows(&state, &check_localhost, Some("localtest")));
+        assert!(!allows(&state, &check(&state, None), Some("localtest")));
+
+        // Other way around is as strict, a certificate asked for localtest says nothing of localhost.
+        let check_localtest = check(&state, Some("localtest"));
+
+        assert!(allows(&state, &check_localtest, Some("localtest")));
+        assert!(!allows(&state, &check_localtest, Some("localhost")));
+    }
+
+    // Without a key, like on a resumed session, names covered by every key of domain count.
+    #[test]
+    fn resumed_session() {
+        let state = state(json!({}), &["localtest"]);
+        let check = HostCheck::new(&state, Some("localhost"), None);
+
+        assert!(allows(&state, &check, Some("localtest")));
+    }
+}
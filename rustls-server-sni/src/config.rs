@@ -0,0 +1,106 @@
+use crate::AnyError;
+use crate::admin::AdminConfig;
+use crate::client_hello::FingerprintRules;
+use crate::coalescing::CoalescingConfig;
+use crate::util::{CheckMode, Domain};
+
+use std::fs::File;
+use std::io::Read;
+
+use middleware::MiddlewareConfig;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub domains: Vec<Domain>,
+    // Every client is accepted when this is missing.
+    #[serde(default)]
+    pub fingerprints: FingerprintRules,
+    // Requests must name SNI of their connection when this is missing.
+    #[serde(default)]
+    pub coalescing: CoalescingConfig,
+    // Whether certificate problems only print warnings or refuse config.
+    #[serde(default)]
+    pub certificate_check: CheckMode,
+    // Admin api is off when this is missing. Only read at startup.
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    // Only read at startup.
+    #[serde(default)]
+    pub middleware: MiddlewareConfig,
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Self, AnyError> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Err(format!("Can't open {:?}.", path).into()),
+        };
+
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+
+        Self::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Self, AnyError> {
+        let config = match text.trim_start().starts_with('[') {
+            // Config used to be just the list of domains, that is still accepted.
+            true => Self::from_domains(serde_json::from_str(text)?),
+            false => serde_json::from_str::<Self>(text)?,
+        };
+
+        config.middleware.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_domains(domains: Vec<Domain>) -> Self {
+        Self {
+            domains,
+            fingerprints: FingerprintRules::default(),
+            coalescing: CoalescingConfig::default(),
+            certificate_check: CheckMode::default(),
+            admin: None,
+            middleware: MiddlewareConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMAINS: &str = r#"[
+        { "name": "localhost", "key_location": "certs/key.pem", "cert_location": "certs/cert.pem" },
+        { "name": "localtest", "key_location": "certs/key.pem", "cert_location": "certs/cert.pem" }
+    ]"#;
+
+    #[test]
+    fn domains_only() {
+        let config = Config::parse(DOMAINS).unwrap();
+
+        assert_eq!(config.domains.len(), 2);
+        assert!(config.admin.is_none());
+    }
+
+    #[test]
+    fn domains_with_settings() {
+        let text = format!(r#"{{ "domains": {}, "fingerprints": {{ "deny": ["abc"] }} }}"#, DOMAINS);
+        let config = Config::parse(&text).unwrap();
+
+        assert_eq!(config.domains.len(), 2);
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        let e = Config::parse("[\n  { \"name\": 1 }\n]").err().unwrap();
+
+        assert!(e.to_string().contains("line 2"), "{}", e);
+
+        let e = Config::parse("{\n  \"domains\": {}\n}").err().unwrap();
+
+        assert!(e.to_string().contains("line 2"), "{}", e);
+    }
+}
@@ -1,66 +1,121 @@
 pub type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
+mod admin;
+mod client_hello;
+mod coalescing;
+mod config;
+mod policy;
+mod reload;
+mod site;
 mod util;
-use util::Domain;
 
-use std::sync::Arc;
+use coalescing::HostCheck;
+use config::Config;
+use reload::{ReloadableConfig, State};
+use site::{Peer, Sni};
+
 use std::convert::Infallible;
+use std::sync::Arc;
 
 use tokio::net::TcpListener;
 
-use tokio_rustls::TlsAcceptor;
-
 use hyper::{Body, Request, Response};
 use hyper::server::conn::Http;
 use hyper::service::service_fn;
 
+use tower::ServiceExt;
+
 #[tokio::main]
 async fn main() -> Result<(), AnyError> {
-    let domains: Vec<Domain> = serde_json::from_reader(
-        std::fs::File::open("config.json")?
-    )?;
+    let check = std::env::args().skip(1).any(|arg| arg == "--check");
+
+    let mut config = Config::from_file("config.json")?;
+
+    if check {
+        reload::check(config)?;
+        println!("configuration is valid");
 
-    let server_config = util::server_config_from(domains)?;
-    let server_config = Arc::new(server_config);
+        return Ok(());
+    }
+
+    let admin = config.admin.take();
+    let service = middleware::stack(service_fn(serve), &config.middleware)?;
+
+    let config = Arc::new(ReloadableConfig::new("config.json", config)?);
+
+    reload::watch(config.clone())?;
+
+    if let Some(admin) = &admin {
+        admin::serve(admin, config.clone()).await?;
+    }
 
     let listener = TcpListener::bind("127.0.0.1:3443").await?;
-    let acceptor = TlsAcceptor::from(server_config);
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let acceptor = acceptor.clone();
+        let (stream, peer) = listener.accept().await?;
+        // Config is picked once, a reload during handshake doesn't affect it.
+        let state = config.current();
+        let service = service.clone();
 
         tokio::spawn(async move {
-            let stream = acceptor.accept(stream).await?;
-            let sni = stream.get_ref().1.get_sni_hostname().ok_or("")?.to_owned();
+            let (mut stream, hello) = client_hello::read(stream).await?;
+            let fingerprint = hello.fingerprint;
 
-            let fut = Http::new()
-                .serve_connection(stream, service_fn(move |req| {
-                    let sni = sni.clone();
+            println!("{} ja3={} ja4={}", peer, fingerprint.ja3_hash, fingerprint.ja4);
 
-                    serve(req, sni)
-                }));
+            if !state.rules.allows(&fingerprint) {
+                println!("{} rejected by fingerprint rules", peer);
 
-            match fut.await {
-                Ok(()) => (),
-                Err(_) => (),
+                return Ok(());
             }
 
+            if !state.resolver.resolves(hello.server_name.as_deref()) {
+                match &hello.server_name {
+                    Some(name) => println!("{} rejected, no certificate for {:?}", peer, name),
+                    None => println!("{} rejected, no sni and no default domain", peer),
+                }
+
+                client_hello::reject_unrecognized_name(&mut stream).await;
+
+                return Ok(());
+            }
+
+            let acceptor = state.policies.acceptor(hello.server_name.as_deref());
+            let (stream, key) = util::record_selected(acceptor.accept(stream)).await;
+            let stream = stream?;
+            let sni = stream.get_ref().1.get_sni_hostname().map(str::to_owned);
+            let host_check = Arc::new(HostCheck::new(&state, sni.as_deref(), key.as_ref()));
+
+            let fut = Http::new()
+                .serve_connection(stream, service_fn(move |mut req| {
+                    req.extensions_mut().insert(Sni(sni.clone()));
+                    req.extensions_mut().insert(fingerprint.clone());
+                    req.extensions_mut().insert(Peer(peer));
+                    req.extensions_mut().insert(state.clone());
+                    req.extensions_mut().insert(host_check.clone());
+
+                    service.clone().oneshot(req)
+                }));
+
+            let _ = fut.await;
+
             Ok::<(), AnyError>(())
         });
     }
 }
 
-async fn serve(
-    _req: Request<Body>,
-    sni: String,
-) -> Result<Response<Body>, Infallible> {
-    let s = format!("SNI is: {}", sni);
+// Behind middleware, connection state comes in request extensions.
+async fn serve(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let state = req.extensions().get::<Arc<State>>().unwrap().clone();
+    let host_check = req.extensions().get::<Arc<HostCheck>>().unwrap().clone();
 
-    let resp = Response::builder()
-        .header("content-type", "text/plain; charset=utf-8")
-        .body(Body::from(s))
-        .unwrap();
+    if !host_check.allows(&req, &state.policies) {
+        let Peer(peer) = req.extensions().get::<Peer>().unwrap();
+
+        println!("{} misdirected request for {:?}", peer, site::host(&req).unwrap_or_default());
+
+        return Ok(coalescing::misdirected());
+    }
 
-    Ok(resp)
+    Ok(state.sites.serve(req).await)
 }
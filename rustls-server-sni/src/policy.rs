@@ -0,0 +1,197 @@
+use crate::AnyError;
+use crate::util::{names, Domain, ResolvesServerCertUsingSNI, Source};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient,
+    AllowAnyAuthenticatedClient,
+    NoClientAuth,
+    ProtocolVersion,
+    RootCertStore,
+    ServerConfig,
+};
+
+use tokio_rustls::TlsAcceptor;
+
+use serde::{Serialize, Deserialize};
+
+/// Handshake settings of a domain, like `{"alpn": ["http/1.1"], "min_version": "1.3"}`.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct TlsPolicy {
+    #[serde(default = "default_alpn")]
+    alpn: Vec<String>,
+    #[serde(default)]
+    min_version: MinVersion,
+    // Clients present a certificate signed by these CAs. Off when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_auth: Option<ClientAuth>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum MinVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct ClientAuth {
+    // Pem bundle, any certificate source works.
+    ca_location: String,
+    // Clients without a certificate are accepted too when false.
+    #[serde(default = "default_required")]
+    required: bool,
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        Self {
+            alpn: default_alpn(),
+            min_version: MinVersion::default(),
+            client_auth: None,
+        }
+    }
+}
+
+/// Acceptor of every domain policy, chosen by SNI before handshake.
+///
+/// Every acceptor resolves certificates with the same resolver.
+pub struct Policies {
+    map: HashMap<String, Entry>,
+    // Policy of default domain, for no SNI or unknown names.
+    default: Entry,
+    // Default policy, for domains without "tls" and names added by admin api.
+    shared: Entry,
+    resolver: Arc<ResolvesServerCertUsingSNI>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    policy: TlsPolicy,
+    acceptor: TlsAcceptor,
+}
+
+impl Policies {
+    pub fn new(domains: &[Domain], resolver: Arc<ResolvesServerCertUsingSNI>) -> Result<Self, AnyError> {
+        let policy = TlsPolicy::default();
+
+        let shared = Entry {
+            acceptor: acceptor(&policy, resolver.clone())
+                .map_err(|e| format!("default tls policy: {}", e))?,
+            policy,
+        };
+
+        let mut map = HashMap::new();
+        let mut default = shared.clone();
+
+        for domain in domains {
+            let entry = match domain.tls() {
+                Some(policy) => Entry {
+                    policy: policy.clone(),
+                    acceptor: acceptor(policy, resolver.clone())
+                        .map_err(|e| format!("tls policy of {:?}: {}", domain.name(), e))?,
+                },
+                None => shared.clone(),
+            };
+
+            if domain.is_default() {
+                default = entry.clone();
+            }
+
+            map.insert(names::normalize(domain.name()), entry);
+        }
+
+        Ok(Self { map, default, shared, resolver })
+    }
+
+    /// Acceptor for a client that sent `sni`.
+    ///
+    /// Policy follows the name whose certificate is served, so a name
+    /// added by admin api gets default policy, not the default domain's.
+    pub fn acceptor(&self, sni: Option<&str>) -> TlsAcceptor {
+        self.entry(sni).acceptor.clone()
+    }
+
+    /// Policy a handshake that sent `sni` is made with, same one [`Policies::acceptor`] uses.
+    pub fn policy(&self, sni: Option<&str>) -> &TlsPolicy {
+        &self.entry(sni).policy
+    }
+
+    fn entry(&self, sni: Option<&str>) -> &Entry {
+        let served = sni.and_then(|sni| self.resolver.served_name(sni));
+
+        match served {
+            Some(name) => self.map.get(&name).unwrap_or(&self.shared),
+            None => &self.default,
+        }
+    }
+}
+
+fn acceptor(policy: &TlsPolicy, resolver: Arc<ResolvesServerCertUsingSNI>) -> Result<TlsAcceptor, AnyError> {
+    let verifier = match &policy.client_auth {
+        Some(auth) => {
+            let roots = root_store(&auth.ca_location)?;
+
+            match auth.required {
+                true => AllowAnyAuthenticatedClient::new(roots),
+                false => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+            }
+        },
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(verifier);
+
+    config.cert_resolver = resolver;
+
+    config.versions = match policy.min_version {
+        MinVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        MinVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+    };
+
+    if policy.alpn.is_empty() {
+        return Err("alpn list is empty".into());
+    }
+
+    for protocol in &policy.alpn {
+        if !matches!(protocol.as_str(), "h2" | "http/1.1") {
+            return Err(format!("unsupported alpn protocol {:?}, use h2 or http/1.1", protocol).into());
+        }
+    }
+
+    let protocols: Vec<Vec<u8>> = policy.alpn.iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect();
+
+    config.set_protocols(&protocols);
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn root_store(location: &str) -> Result<RootCertStore, AnyError> {
+    let source = Source::parse(location);
+    let pem = source.read()?;
+
+    let mut roots = RootCertStore::empty();
+
+    let (valid, _) = roots.add_pem_file(&mut pem.as_slice())
+        .map_err(|_| format!("cant get certificates from {}", source))?;
+
+    if valid == 0 {
+        return Err(format!("did not find ca certificate in {}", source).into());
+    }
+
+    Ok(roots)
+}
+
+fn default_alpn() -> Vec<String> {
+    vec!["h2".to_owned(), "http/1.1".to_owned()]
+}
+
+fn default_required() -> bool {
+    true
+}
@@ -0,0 +1,462 @@
+use crate::AnyError;
+use crate::client_hello::FingerprintRules;
+use crate::coalescing::CoalescingConfig;
+use crate::config::Config;
+use crate::policy::Policies;
+use crate::site::Sites;
+use crate::util::{self, names, CheckMode, ResolvesServerCertUsingSNI};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::iter;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::Certificate;
+use rustls::sign::CertifiedKey;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+
+// How often config file and files it names are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything built from config file, used by a handshake from start to end.
+pub struct State {
+    pub policies: Policies,
+    pub resolver: Arc<ResolvesServerCertUsingSNI>,
+    pub rules: FingerprintRules,
+    pub sites: Sites,
+    pub coalescing: CoalescingConfig,
+    pub certificate_check: CheckMode,
+    // Domains by normalized name, to tell what a reload changed.
+    domains: BTreeMap<String, Loaded>,
+    // Key and certificate files, watched for changes like config file.
+    files: BTreeSet<String>,
+}
+
+// What a domain was loaded from. Files can change while config entry stays same.
+#[derive(PartialEq)]
+struct Loaded {
+    entry: String,
+    chains: Vec<Vec<Certificate>>,
+}
+
+/// Config that can be loaded again while server is running.
+///
+/// New handshakes get new config, running connections keep the one they started with.
+pub struct ReloadableConfig {
+    path: String,
+    current: RwLock<Arc<State>>,
+    // Locked while admin api or a reload changes domains.
+    overlay: Mutex<Overlay>,
+}
+
+// Changes made by admin api, applied again after every reload.
+//
+// A change is dropped once config file changes entry of its name.
+#[derive(Default)]
+struct Overlay {
+    added: BTreeMap<String, Vec<CertifiedKey>>,
+    // Only names config file has.
+    removed: BTreeSet<String>,
+}
+
+impl ReloadableConfig {
+    /// Serve `config`, later reloads read it from `path`.
+    pub fn new(path: &str, config: Config) -> Result<Self, AnyError> {
+        let state = build(config)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            current: RwLock::new(Arc::new(state)),
+            overlay: Mutex::new(Overlay::default()),
+        })
+    }
+
+    /// Load config again and apply admin api changes to it. Previous one is kept on error.
+    pub fn reload(&self) -> Result<(), AnyError> {
+        let state = load(&self.path)?;
+        let mut overlay = self.overlay.lock().unwrap();
+
+        let changed = log_changes(&self.current().domains, &state.domains);
+
+        overlay.apply(&state.resolver, &changed);
+
+        *self.current.write().unwrap() = Arc::new(state);
+
+        Ok(())
+    }
+
+    /// Serve `keys` for `name` until config file changes it. Returns whether `name` was served already.
+    pub fn add(&self, name: &str, keys: Vec<CertifiedKey>) -> Result<bool, AnyError> {
+        let mut overlay = self.overlay.lock().unwrap();
+        let resolver = self.current().resolver.clone();
+        let existed = resolver.contains(name);
+
+        resolver.add(name, keys.clone())?;
+
+        let name = names::normalize(name);
+
+        overlay.removed.remove(&name);
+        overlay.added.insert(name, keys);
+
+        Ok(existed)
+    }
+
+    /// Stop serving `name` until config file changes it. Returns false if it wasn't served.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut overlay = self.overlay.lock().unwrap();
+        let state = self.current();
+
+        if !state.resolver.remove(name) {
+            return false;
+        }
+
+        let name = names::normalize(name);
+
+        overlay.added.remove(&name);
+
+        if state.domains.contains_key(&name) {
+            overlay.removed.insert(name);
+        }
+
+        true
+    }
+
+    pub fn current(&self) -> Arc<State> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Modification times of config file and key and certificate files it names.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let state = self.current();
+
+        iter::once(&self.path)
+            .chain(&state.files)
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// Spawn a task that reloads config on SIGHUP and when its file, or a key or certificate file, changes.
+pub fn watch(config: Arc<ReloadableConfig>) -> Result<(), AnyError> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interval = time::interval(WATCH_INTERVAL);
+    let mut modified = config.modified();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => (),
+                _ = interval.tick() => {
+                    if config.modified() == modified {
+                        continue;
+                    }
+                },
+            }
+
+            match config.reload() {
+                Ok(()) => println!("config reloaded"),
+                Err(e) => println!("error reloading config, keeping previous one: {}", e),
+            }
+
+            // Files named by new config are watched from now on.
+            modified = config.modified();
+        }
+    });
+
+    Ok(())
+}
+
+fn load(path: &str) -> Result<State, AnyError> {
+    build(Config::from_file(path)?)
+}
+
+fn build(config: Config) -> Result<State, AnyError> {
+    let resolver = util::resolver_from(&config.domains)?;
+    let served: BTreeMap<_, _> = resolver.list().into_iter().collect();
+
+    let mut domains = BTreeMap::new();
+    let mut files = BTreeSet::new();
+
+    for domain in &config.domains {
+        let name = names::normalize(domain.name());
+
+        let chains = served.get(&name)
+            .map(|keys| keys.iter().map(|key| key.cert.clone()).collect())
+            .unwrap_or_default();
+
+        let loaded = Loaded {
+            entry: serde_json::to_string(domain)?,
+            chains,
+        };
+
+        domains.insert(name, loaded);
+        files.extend(domain.files().into_iter().map(str::to_owned));
+    }
+    let sites = Sites::new(&config.domains, resolver.clone())?;
+
+    check_certificates(&resolver, config.certificate_check)?;
+
+    let policies = Policies::new(&config.domains, resolver.clone())?;
+
+    Ok(State {
+        policies,
+        resolver,
+        rules: config.fingerprints,
+        sites,
+        coalescing: config.coalescing,
+        certificate_check: config.certificate_check,
+        domains,
+        files,
+    })
+}
+
+/// State built from `config` json, for tests of modules using it.
+#[cfg(test)]
+pub fn test_state(config: serde_json::Value) -> State {
+    build(serde_json::from_value(config).unwrap()).unwrap()
+}
+
+/// Load `config` as server would and print every certificate problem. Fails if there are any.
+pub fn check(config: Config) -> Result<(), AnyError> {
+    let resolver = util::resolver_from(&config.domains)?;
+
+    Sites::new(&config.domains, resolver.clone())?;
+    Policies::new(&config.domains, resolver.clone())?;
+
+    check_certificates(&resolver, CheckMode::Fail)
+}
+
+fn check_certificates(resolver: &ResolvesServerCertUsingSNI, mode: CheckMode) -> Result<(), AnyError> {
+    let domains = resolver.list();
+    let mut failed = 0;
+
+    for (name, keys) in &domains {
+        let problems = util::check_keys(name, keys);
+
+        for problem in &problems {
+            println!("certificate problem of {:?}: {}", name, problem);
+        }
+
+        if !problems.is_empty() {
+            failed += 1;
+        }
+    }
+
+    match (mode, failed) {
+        (CheckMode::Fail, 1..) => {
+            Err(format!("certificate check failed for {} of {} domains", failed, domains.len()).into())
+        },
+        _ => Ok(()),
+    }
+}
+
+// Returns names that were added, changed or removed. A domain whose files changed counts as changed.
+fn log_changes(old: &BTreeMap<String, Loaded>, new: &BTreeMap<String, Loaded>) -> BTreeSet<String> {
+    let mut changed = BTreeSet::new();
+
+    for (name, domain) in new {
+        match old.get(name) {
+            None => println!("domain added: {}", name),
+            Some(previous) if previous != domain => println!("domain changed: {}", name),
+            Some(_) => continue,
+        }
+
+        changed.insert(name.clone());
+    }
+
+    for name in old.keys().filter(|name| !new.contains_key(*name)) {
+        println!("domain removed: {}", name);
+
+        changed.insert(name.clone());
+    }
+
+    changed
+}
+
+impl Overlay {
+    // Apply to `resolver` of a reload, `changed` names of config file win over admin api.
+    fn apply(&mut self, resolver: &ResolvesServerCertUsingSNI, changed: &BTreeSet<String>) {
+        self.added.retain(|name, keys| {
+            if changed.contains(name) {
+                println!("admin api upload dropped, config changed domain: {}", name);
+                return false;
+            }
+
+            match resolver.add(name, keys.clone()) {
+                Ok(()) => {
+                    println!("admin api upload kept: {}", name);
+                    true
+                },
+                Err(e) => {
+                    println!("admin api upload of {} dropped: {}", name, e);
+                    false
+                },
+            }
+        });
+
+        self.removed.retain(|name| {
+            if changed.contains(name) {
+                println!("admin api removal dropped, config changed domain: {}", name);
+                return false;
+            }
+
+            resolver.remove(name);
+            println!("admin api removal kept: {}", name);
+
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::Source;
+
+    use std::path::{Path, PathBuf};
+
+    use serde_json::json;
+
+    fn key() -> CertifiedKey {
+        util::certified_key("localhost", &Source::parse("certs/key.pem"), &Source::parse("certs/cert.pem")).unwrap()
+    }
+
+    // Resolver of a reloaded config serving `names`.
+    fn resolver(names: &[&str]) -> ResolvesServerCertUsingSNI {
+        let resolver = ResolvesServerCertUsingSNI::new();
+
+        for name in names {
+            resolver.add(name, vec![key()]).unwrap();
+        }
+
+        resolver
+    }
+
+    fn served(resolver: &ResolvesServerCertUsingSNI) -> Vec<String> {
+        resolver.list().into_iter().map(|(name, _)| name).collect()
+    }
+
+    fn changed(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn changes_kept() {
+        let mut overlay = Overlay::default();
+
+        overlay.added.insert("uploaded".to_owned(), vec![key()]);
+        overlay.removed.insert("localtest".to_owned());
+
+        let resolver = resolver(&["localhost", "localtest"]);
+
+        overlay.apply(&resolver, &changed(&["other"]));
+
+        assert_eq!(served(&resolver), ["localhost", "uploaded"]);
+        assert!(overlay.added.contains_key("uploaded"));
+        assert!(overlay.removed.contains("localtest"));
+    }
+
+    // Config file wins over admin api for domains it changes.
+    #[test]
+    fn changes_dropped() {
+        let mut overlay = Overlay::default();
+
+        overlay.added.insert("localhost".to_owned(), vec![key()]);
+        overlay.removed.insert("localtest".to_owned());
+
+        let resolver = resolver(&["localtest"]);
+
+        overlay.apply(&resolver, &changed(&["localhost", "localtest"]));
+
+        // Removed from config file, upload isn't brought back.
+        assert_eq!(served(&resolver), ["localtest"]);
+        assert!(overlay.added.is_empty());
+        assert!(overlay.removed.is_empty());
+    }
+
+    // Directory with config.json serving "localhost" from copies of certs/key.pem and certs/cert.pem.
+    fn config_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustls-server-sni-{}-{}", test, std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("certs/key.pem", dir.join("key.pem")).unwrap();
+        fs::copy("certs/cert.pem", dir.join("cert.pem")).unwrap();
+
+        let config = json!({
+            "domains": [{
+                "name": "localhost",
+                "key_location": dir.join("key.pem"),
+                "cert_location": dir.join("cert.pem"),
+            }]
+        });
+
+        fs::write(dir.join("config.json"), config.to_string()).unwrap();
+
+        dir
+    }
+
+    fn reloadable(dir: &Path) -> ReloadableConfig {
+        let path = dir.join("config.json");
+        let path = path.to_str().unwrap();
+
+        ReloadableConfig::new(path, Config::from_file(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn failed_reload_keeps_state() {
+        let dir = config_dir("failed-reload");
+        let config = reloadable(&dir);
+        let before = config.current();
+
+        fs::write(dir.join("config.json"), "{ not json").unwrap();
+        assert!(config.reload().is_err());
+
+        fs::write(dir.join("config.json"), json!({ "domains": [{
+            "name": "localtest",
+            "key_location": dir.join("key.pem"),
+            "cert_location": dir.join("missing.pem"),
+        }]}).to_string()).unwrap();
+        assert!(config.reload().is_err());
+
+        assert!(Arc::ptr_eq(&before, &config.current()));
+        assert_eq!(served(&config.current().resolver), ["localhost"]);
+    }
+
+    #[test]
+    fn certificate_files_watched() {
+        let dir = config_dir("files-watched");
+        let config = reloadable(&dir);
+        let before = config.current();
+        let modified = config.modified();
+
+        assert_eq!(modified.len(), 3);
+
+        fs::copy("certs/test/ecdsa-p256.key.pem", dir.join("key.pem")).unwrap();
+        fs::copy("certs/test/ecdsa-p256.cert.pem", dir.join("cert.pem")).unwrap();
+
+        // Copies may land within timestamp granularity of originals.
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options().write(true).open(dir.join("cert.pem")).unwrap().set_modified(later).unwrap();
+
+        assert_ne!(config.modified(), modified);
+
+        config.reload().unwrap();
+
+        assert_eq!(log_changes(&before.domains, &config.current().domains), changed(&["localhost"]));
+    }
+
+    #[test]
+    fn unchanged_files_not_logged() {
+        let dir = config_dir("files-unchanged");
+        let config = reloadable(&dir);
+        let before = config.current();
+
+        config.reload().unwrap();
+
+        assert!(log_changes(&before.domains, &config.current().domains).is_empty());
+    }
+}
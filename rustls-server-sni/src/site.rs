@@ -0,0 +1,475 @@
+use crate::AnyError;
+use crate::client_hello::Fingerprint;
+use crate::util::{names, Domain, ResolvesServerCertUsingSNI};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use hyper::{Body, Client, Request, Response, StatusCode, Version};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, CONTENT_TYPE, HOST, LOCATION};
+use hyper::http::uri::Authority;
+
+use hop_headers::remove_hop_headers;
+
+use percent_encoding::percent_decode_str;
+
+use serde::{Serialize, Deserialize};
+
+/// What a domain serves, like `{"root": "www/example"}`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum SiteConfig {
+    // Files under a directory, "index.html" for directories.
+    Root(String),
+    // Permanent redirect to this url with request path and query appended.
+    Redirect(String),
+    // Plaintext http server as "host:port".
+    Proxy(String),
+    Builtin(Builtin),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Builtin {
+    // Plaintext page showing SNI and fingerprints of client.
+    Info,
+}
+
+/// Server name sent by client, if any. Inserted into request extensions.
+#[derive(Clone)]
+pub struct Sni(pub Option<String>);
+
+/// Address of client. Inserted into request extensions.
+#[derive(Clone, Copy)]
+pub struct Peer(pub SocketAddr);
+
+enum Site {
+    Root(PathBuf),
+    Redirect(String),
+    Proxy {
+        client: Client<HttpConnector>,
+        authority: String,
+    },
+    Builtin(Builtin),
+}
+
+/// Sites of configured domains, chosen by Host and SNI.
+pub struct Sites {
+    map: HashMap<String, Arc<Site>>,
+    // Site of default domain.
+    default: Option<Arc<Site>>,
+    // For domains without "serve" and names added by admin api.
+    fallback: Arc<Site>,
+    // Names are looked up as certificates are, to tell names added by admin api.
+    resolver: Arc<ResolvesServerCertUsingSNI>,
+}
+
+impl Sites {
+    pub fn new(domains: &[Domain], resolver: Arc<ResolvesServerCertUsingSNI>) -> Result<Self, AnyError> {
+        let client = Client::new();
+
+        let mut map = HashMap::new();
+        let mut default = None;
+
+        for domain in domains {
+            let site = match domain.serve() {
+                Some(config) => Site::new(domain.name(), config, &client)?,
+                None => Site::Builtin(Builtin::Info),
+            };
+
+            let site = Arc::new(site);
+
+            if domain.is_default() {
+                default = Some(site.clone());
+            }
+
+            map.insert(names::normalize(domain.name()), site);
+        }
+
+        Ok(Self {
+            map,
+            default,
+            fallback: Arc::new(Site::Builtin(Builtin::Info)),
+            resolver,
+        })
+    }
+
+    /// Serve `req` with site of its Host, or of SNI when Host names no domain.
+    pub async fn serve(&self, req: Request<Body>) -> Response<Body> {
+        let sni = req.extensions().get::<Sni>().and_then(|Sni(sni)| sni.as_deref());
+
+        let site = host(&req).and_then(|host| self.lookup(&host))
+            .or_else(|| sni.and_then(|sni| self.lookup(sni)))
+            .or_else(|| self.default.clone())
+            .unwrap_or_else(|| self.fallback.clone());
+
+        site.serve(req).await
+    }
+
+    // Site of the name whose certificate is served for `name`, fallback
+    // for names added by admin api. `None` when no name is served.
+    fn lookup(&self, name: &str) -> Option<Arc<Site>> {
+        let served = self.resolver.served_name(name)?;
+
+        Some(self.map.get(&served).unwrap_or(&self.fallback).clone())
+    }
+}
+
+impl Site {
+    fn new(name: &str, config: &SiteConfig, client: &Client<HttpConnector>) -> Result<Self, AnyError> {
+        let site = match config {
+            SiteConfig::Root(root) => {
+                let root = PathBuf::from(root);
+
+                if !root.is_dir() {
+                    return Err(format!("root of {:?} is not a directory: {:?}", name, root).into());
+                }
+
+                Site::Root(root)
+            },
+            SiteConfig::Redirect(to) => {
+                to.parse::<hyper::Uri>()
+                    .map_err(|_| format!("invalid redirect url of {:?}", name))?;
+
+                Site::Redirect(to.trim_end_matches('/').to_owned())
+            },
+            SiteConfig::Proxy(authority) => {
+                authority.parse::<Authority>()
+                    .map_err(|_| format!("invalid proxy upstream of {:?}, use host:port", name))?;
+
+                Site::Proxy {
+                    client: client.clone(),
+                    authority: authority.clone(),
+                }
+            },
+            SiteConfig::Builtin(builtin) => Site::Builtin(*builtin),
+        };
+
+        Ok(site)
+    }
+
+    async fn serve(&self, req: Request<Body>) -> Response<Body> {
+        match self {
+            Site::Root(root) => serve_file(root, &req).await,
+            Site::Redirect(to) => redirect(to, &req),
+            Site::Proxy { client, authority } => {
+                match forward(client, authority, req).await {
+                    Ok(resp) => resp,
+                    Err(_) => text_response(StatusCode::BAD_GATEWAY, "Bad Gateway"),
+                }
+            },
+            Site::Builtin(Builtin::Info) => info(&req),
+        }
+    }
+}
+
+/// Host header, or uri authority for http2. Port is left out.
+pub fn host(req: &Request<Body>) -> Option<String> {
+    let authority = match req.headers().get(HOST) {
+        Some(host) => host.to_str().ok()?.parse::<Authority>().ok()?,
+        None => req.uri().authority()?.clone(),
+    };
+
+    let host = authority.host();
+
+    Some(host.trim_start_matches('[').trim_end_matches(']').to_owned())
+}
+
+async fn serve_file(root: &Path, req: &Request<Body>) -> Response<Body> {
+    let path = match percent_decode_str(req.uri().path()).decode_utf8() {
+        Ok(path) => path,
+        Err(_) => return text_response(StatusCode::BAD_REQUEST, "Bad Request"),
+    };
+
+    let relative = Path::new(path.trim_start_matches('/'));
+
+    // Only plain names, nothing can point outside of root.
+    if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return text_response(StatusCode::NOT_FOUND, "Not Found");
+    }
+
+    let mut file = root.join(relative);
+
+    if file.is_dir() {
+        file.push("index.html");
+    }
+
+    match tokio::fs::read(&file).await {
+        Ok(data) => Response::builder()
+            .header(CONTENT_TYPE, content_type(&file))
+            .body(Body::from(data))
+            .unwrap(),
+        Err(_) => text_response(StatusCode::NOT_FOUND, "Not Found"),
+    }
+}
+
+fn content_type(file: &Path) -> &'static str {
+    match file.extension().and_then(|e| e.to_str()).unwrap_or("") {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn redirect(to: &str, req: &Request<Body>) -> Response<Body> {
+    let path = req.uri().path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(LOCATION, format!("{}{}", to, path))
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn forward(
+    client: &Client<HttpConnector>,
+    authority: &str,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, AnyError> {
+    let host = host(&req);
+    let peer = req.extensions().get::<Peer>().map(|Peer(peer)| *peer);
+
+    let path = req.uri().path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    *req.uri_mut() = format!("http://{}{}", authority, path).parse()?;
+    *req.version_mut() = Version::HTTP_11;
+
+    let headers = req.headers_mut();
+
+    remove_hop_headers(headers);
+
+    if let Some(host) = host {
+        let host = HeaderValue::from_str(&host)?;
+
+        headers.insert(HOST, host.clone());
+        headers.insert("x-forwarded-host", host);
+    }
+
+    if let Some(peer) = peer {
+        headers.insert("x-forwarded-for", HeaderValue::from_str(&peer.ip().to_string())?);
+    }
+
+    headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+
+    let mut resp = client.request(req).await?;
+
+    remove_hop_headers(resp.headers_mut());
+
+    Ok(resp)
+}
+
+fn info(req: &Request<Body>) -> Response<Body> {
+    let sni = req.extensions().get::<Sni>().and_then(|Sni(sni)| sni.as_deref());
+    let fingerprint = req.extensions().get::<Fingerprint>().unwrap();
+
+    let s = format!(
+        "SNI is: {}\nJA3 is: {}\nJA4 is: {}",
+        sni.unwrap_or("(none)"),
+        fingerprint.ja3_hash,
+        fingerprint.ja4,
+    );
+
+    text_response(StatusCode::OK, &s)
+}
+
+fn text_response(status: StatusCode, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body.to_owned()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::reload;
+
+    use std::fs;
+
+    use serde_json::json;
+
+    // Root with index.html and a.txt, next to a file that must not be served.
+    fn root(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustls-server-sni-{}-{}", test, std::process::id()));
+        let root = dir.join("www");
+
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("index.html"), "index").unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+
+        root
+    }
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    async fn text(resp: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_files() {
+        let root = root("files");
+
+        let resp = serve_file(&root, &request("/")).await;
+
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(text(resp).await, "index");
+
+        let resp = serve_file(&root, &request("/a.txt")).await;
+
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(text(resp).await, "a");
+
+        let resp = serve_file(&root, &request("/missing.txt")).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn traversal_rejected() {
+        let root = root("traversal");
+
+        // Reachable as a plain name, so only traversal makes the difference.
+        assert!(root.join("../secret.txt").is_file());
+
+        let paths = [
+            "/../secret.txt",
+            "/sub/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/%2E%2E/secret.txt",
+            "/sub/%2e%2e/%2e%2e/secret.txt",
+            "/%2e%2e%2fsecret.txt",
+            "/./a.txt",
+        ];
+
+        for path in paths {
+            let resp = serve_file(&root, &request(path)).await;
+
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        let resp = serve_file(&root, &request("/%ff")).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn redirects() {
+        let resp = redirect("https://example.com", &request("/a/b?x=1"));
+
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers()[LOCATION], "https://example.com/a/b?x=1");
+
+        assert_eq!(redirect("https://example.com", &request("/")).headers()[LOCATION], "https://example.com/");
+    }
+
+    #[test]
+    fn redirect_config() {
+        let client = Client::new();
+
+        let site = Site::new("localhost", &SiteConfig::Redirect("https://example.com/".to_owned()), &client).unwrap();
+
+        assert!(matches!(site, Site::Redirect(ref to) if to == "https://example.com"));
+        assert!(Site::new("localhost", &SiteConfig::Redirect("not a url".to_owned()), &client).is_err());
+        assert!(Site::new("localhost", &SiteConfig::Proxy("http://x".to_owned()), &client).is_err());
+        assert!(Site::new("localhost", &SiteConfig::Root("/nonexistent".to_owned()), &client).is_err());
+    }
+
+    fn state(root: &Path) -> reload::State {
+        let domain = |name: &str, serve: serde_json::Value| json!({
+            "name": name,
+            "key_location": "certs/key.pem",
+            "cert_location": "certs/cert.pem",
+            "serve": serve,
+        });
+
+        let mut localtest = domain("localtest", json!({ "root": root }));
+
+        localtest["default"] = json!(true);
+
+        reload::test_state(json!({
+            "domains": [
+                domain("*.example.com", json!({ "redirect": "https://example.com" })),
+                domain("localhost", json!({ "builtin": "info" })),
+                localtest,
+            ],
+        }))
+    }
+
+    #[test]
+    fn lookup() {
+        let state = state(&root("lookup"));
+        let sites = &state.sites;
+
+        assert!(matches!(*sites.lookup("WWW.example.com").unwrap(), Site::Redirect(_)));
+        assert!(matches!(*sites.lookup("localtest").unwrap(), Site::Root(_)));
+        assert!(matches!(*sites.lookup("localhost").unwrap(), Site::Builtin(Builtin::Info)));
+
+        // Default domain is only used by serve.
+        assert!(sites.lookup("example.com").is_none());
+        assert!(sites.lookup("a.b.example.com").is_none());
+
+        // Names added by admin api get fallback site.
+        state.resolver.add("added.test", state.resolver.keys(Some("localtest")).unwrap()).unwrap();
+
+        assert!(Arc::ptr_eq(&sites.lookup("added.test").unwrap(), &sites.fallback));
+    }
+
+    #[tokio::test]
+    async fn site_by_host_then_sni() {
+        let state = state(&root("serve"));
+
+        let mut req = Request::builder()
+            .uri("/a.txt")
+            .header(HOST, "www.example.com:3443")
+            .body(Body::empty())
+            .unwrap();
+
+        req.extensions_mut().insert(Sni(Some("localtest".to_owned())));
+
+        let resp = state.sites.serve(req).await;
+
+        assert_eq!(resp.headers()[LOCATION], "https://example.com/a.txt");
+
+        // Host names no domain, so SNI picks site.
+        let mut req = Request::builder()
+            .uri("/a.txt")
+            .header(HOST, "unknown.test")
+            .body(Body::empty())
+            .unwrap();
+
+        req.extensions_mut().insert(Sni(Some("www.example.com".to_owned())));
+
+        let resp = state.sites.serve(req).await;
+
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+
+        // Neither does, default domain serves it.
+        let resp = state.sites.serve(request("/a.txt")).await;
+
+        assert_eq!(text(resp).await, "a");
+    }
+}
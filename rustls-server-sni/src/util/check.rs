@@ -0,0 +1,12 @@
+use serde::{Serialize, Deserialize};
+
+/// What happens when a certificate has problems.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckMode {
+    // Print problems and serve anyway.
+    #[default]
+    Warn,
+    // Refuse config or upload.
+    Fail,
+}
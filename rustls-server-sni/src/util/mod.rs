@@ -1,76 +1,213 @@
+mod check;
+pub mod names;
 mod test;
 // only for testing
-use test::ResolvesServerCertUsingSNI;
+pub use test::{record_selected, ResolvesServerCertUsingSNI};
 // for production:
 // rustls::ResolvesServerCertUsingSNI;
 
 use crate::AnyError;
+use crate::policy::TlsPolicy;
+use crate::site::SiteConfig;
+
+pub use check::CheckMode;
+pub use cert_check::{certificate_names, check_keys};
+pub use pem_source::Source;
 
-use std::fs::File;
-use std::io::BufReader;
 use std::sync::Arc;
 
-use rustls::{
-    ServerConfig,
-    NoClientAuth,
-    PrivateKey,
-};
-use rustls::internal::pemfile::{certs, pkcs8_private_keys};
-use rustls::sign::{RSASigningKey, CertifiedKey, SigningKey};
+use rustls::{PrivateKey, SignatureScheme};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{any_supported_type, CertifiedKey};
 
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize)]
 pub struct Domain {
     name: String,
+    // Single pair, same as one entry in "certificates".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cert_location: Option<String>,
+    // Several pairs, like RSA and ECDSA. Client gets best one it supports.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    certificates: Vec<CertPair>,
+    // Served to clients that send no SNI or a name no domain matches.
+    #[serde(default)]
+    default: bool,
+    // Built-in info page when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    serve: Option<SiteConfig>,
+    // Alpn, tls versions and client certificates. Shared defaults when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls: Option<TlsPolicy>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CertPair {
     key_location: String,
     cert_location: String,
 }
 
-pub fn server_config_from(
-    domains: Vec<Domain>,
-) -> Result<ServerConfig, AnyError> {
-    let mut sconfig = ServerConfig::new(
-        NoClientAuth::new()
-    );
+impl Domain {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-    let mut sni_resolver = ResolvesServerCertUsingSNI::new();
+    pub fn is_default(&self) -> bool {
+        self.default
+    }
 
-    for domain in domains {
-        let privkey = get_first_private_key(&domain.key_location)?;
-        let rsa_key = RSASigningKey::new(&privkey)
-            .map_err(|_| "cant get rsa key")?;
-        
-        let cert = certs(
-            &mut BufReader::new(
-                File::open(&domain.cert_location)?
-            )
-        ).map_err(|_| "cant get certificates")?;
+    pub fn serve(&self) -> Option<&SiteConfig> {
+        self.serve.as_ref()
+    }
+
+    pub fn tls(&self) -> Option<&TlsPolicy> {
+        self.tls.as_ref()
+    }
+
+    /// Paths of key and certificate files. Other sources aren't included.
+    pub fn files(&self) -> Vec<&str> {
+        self.pairs().unwrap_or_default()
+            .into_iter()
+            .flat_map(|(key, cert)| [key, cert])
+            .filter_map(|location| match Source::parse(location) {
+                Source::File(path) => Some(path),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn pairs(&self) -> Result<Vec<(&str, &str)>, AnyError> {
+        let mut pairs: Vec<_> = self.certificates.iter()
+            .map(|p| (p.key_location.as_str(), p.cert_location.as_str()))
+            .collect();
 
-        let signing_key: Box<dyn SigningKey> = Box::new(rsa_key);
+        match (&self.key_location, &self.cert_location) {
+            (Some(key), Some(cert)) => pairs.insert(0, (key.as_str(), cert.as_str())),
+            (None, None) => (),
+            _ => return Err(format!("{:?} needs both key_location and cert_location", self.name).into()),
+        }
 
-        let certified_key = CertifiedKey::new(cert, Arc::new(signing_key));
+        if pairs.is_empty() {
+            return Err(format!("{:?} has no certificates", self.name).into());
+        }
 
-        sni_resolver.add(&domain.name, certified_key)?;
+        Ok(pairs)
     }
+}
+
+/// Resolver that serves `domains`, also used to check names before handshake.
+pub fn resolver_from(domains: &[Domain]) -> Result<Arc<ResolvesServerCertUsingSNI>, AnyError> {
+    let sni_resolver = ResolvesServerCertUsingSNI::new();
+
+    for domain in domains {
+        let keys = domain.pairs()?
+            .into_iter()
+            .map(|(key, cert)| certified_key(&domain.name, &Source::parse(key), &Source::parse(cert)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        sni_resolver.add(&domain.name, keys)?;
+
+        if domain.default {
+            sni_resolver.set_default(&domain.name)
+                .map_err(|_| format!("{:?} can't be default, another domain already is", domain.name))?;
+        }
+    }
+
+    Ok(Arc::new(sni_resolver))
+}
 
-    sconfig.cert_resolver = Arc::new(sni_resolver);
+/// Key and certificate chain of `name` read from `key` and `cert`.
+pub fn certified_key(name: &str, key: &Source, cert: &Source) -> Result<CertifiedKey, AnyError> {
+    let privkey = get_first_private_key(key)?;
 
-    sconfig.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    // RSA, ECDSA P-256/P-384 and Ed25519 are detected from key itself.
+    let signing_key = any_supported_type(&privkey)
+        .map_err(|_| format!("unsupported key type for {:?}, use RSA, ECDSA P-256/P-384 or Ed25519", name))?;
 
-    Ok(sconfig)
+    let chain = certs(&mut cert.read()?.as_slice())
+        .map_err(|_| format!("cant get certificates from {}", cert))?;
+
+    if chain.is_empty() {
+        return Err(format!("did not find certificate in {}", cert).into());
+    }
+
+    Ok(CertifiedKey::new(chain, Arc::new(signing_key)))
 }
 
-fn get_first_private_key(loc: &str) -> Result<PrivateKey, AnyError> {
-    let mut privkeys = pkcs8_private_keys(
-        &mut BufReader::new(
-            File::open(&loc)?
-        )
-    ).map_err(|_| "cant get private key")?;
+/// Type of private key of `key`, like "ECDSA P-256".
+pub fn key_type(key: &CertifiedKey) -> &'static str {
+    // Told by a scheme key can sign with, rustls reports no algorithm for Ed25519 keys.
+    let signs = |scheme| key.key.choose_scheme(&[scheme]).is_some();
+
+    if signs(SignatureScheme::RSA_PKCS1_SHA256) {
+        "RSA"
+    } else if signs(SignatureScheme::ECDSA_NISTP256_SHA256) {
+        "ECDSA P-256"
+    } else if signs(SignatureScheme::ECDSA_NISTP384_SHA384) {
+        "ECDSA P-384"
+    } else if signs(SignatureScheme::ED25519) {
+        "Ed25519"
+    } else {
+        "unknown"
+    }
+}
+
+// Pkcs8 keys of any type, or pkcs1 "RSA PRIVATE KEY".
+fn get_first_private_key(source: &Source) -> Result<PrivateKey, AnyError> {
+    let pem = source.read()?;
+
+    let mut privkeys = pkcs8_private_keys(&mut pem.as_slice())
+        .map_err(|_| format!("cant get private key from {}", source))?;
+
+    if privkeys.is_empty() {
+        privkeys = rsa_private_keys(&mut pem.as_slice())
+            .map_err(|_| format!("cant get private key from {}", source))?;
+    }
 
     if !privkeys.is_empty() {
         Ok(privkeys.remove(0))
     } else {
-        Err("did not find private key in file".into())
+        Err(format!("did not find private key in {}", source).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(key: &str, cert: &str) -> Result<CertifiedKey, AnyError> {
+        certified_key("localhost", &Source::parse(key), &Source::parse(cert))
+    }
+
+    #[test]
+    fn key_types() {
+        let cases = [
+            ("certs/key.pem", "certs/cert.pem", "RSA"),
+            ("certs/test/rsa-pkcs1.key.pem", "certs/cert.pem", "RSA"),
+            ("certs/test/ecdsa-p256.key.pem", "certs/test/ecdsa-p256.cert.pem", "ECDSA P-256"),
+            ("certs/test/ecdsa-p384.key.pem", "certs/test/ecdsa-p384.cert.pem", "ECDSA P-384"),
+            ("certs/test/ed25519.key.pem", "certs/test/ed25519.cert.pem", "Ed25519"),
+        ];
+
+        for (key, cert, expected) in cases {
+            assert_eq!(key_type(&load(key, cert).unwrap()), expected, "{}", key);
+        }
+    }
+
+    #[test]
+    fn unsupported_key_type() {
+        let e = load("certs/test/ecdsa-p521.key.pem", "certs/cert.pem").err().unwrap();
+
+        assert!(e.to_string().starts_with("unsupported key type for \"localhost\""), "{}", e);
+    }
+
+    #[test]
+    fn missing_key() {
+        let e = load("certs/cert.pem", "certs/cert.pem").err().unwrap();
+
+        assert_eq!(e.to_string(), "did not find private key in \"certs/cert.pem\"");
     }
 }
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+/// Lowercase `name` without trailing dot, as names are stored.
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Only whole leftmost label can be "*", and at least two labels must follow it,
+// so "*.com" that would match every name of a top level domain is refused.
+pub fn is_valid_wildcard(name: &str) -> bool {
+    match name.strip_prefix("*.") {
+        Some(rest) => is_wildcard_suffix(rest),
+        None => false,
+    }
+}
+
+fn is_wildcard_suffix(rest: &str) -> bool {
+    !rest.contains('*') && rest.contains('.') && rest.split('.').all(|l| !l.is_empty())
+}
+
+/// Whether `pattern`, a name or a wildcard, matches `name`.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = normalize(pattern);
+    let name = normalize(name);
+
+    if pattern == name {
+        return true;
+    }
+
+    match (pattern.strip_prefix("*."), name.split_once('.')) {
+        (Some(suffix), Some((label, rest))) => {
+            !label.is_empty() && rest == suffix && is_wildcard_suffix(suffix)
+        },
+        _ => false,
+    }
+}
+
+/// Value of `name` in `map` keyed by normalized names.
+///
+/// A leftmost `*` label matches exactly one label, exact names win over wildcards.
+pub fn lookup<'a, V>(map: &'a HashMap<String, V>, name: &str) -> Option<&'a V> {
+    lookup_entry(map, name).map(|(_, value)| value)
+}
+
+/// Key and value of `name` in `map`, key is the wildcard when that matched.
+pub fn lookup_entry<'a, V>(map: &'a HashMap<String, V>, name: &str) -> Option<(&'a String, &'a V)> {
+    let name = normalize(name);
+
+    if let Some(entry) = map.get_key_value(&name) {
+        return Some(entry);
+    }
+
+    match name.split_once('.') {
+        Some((label, rest)) if !label.is_empty() && is_wildcard_suffix(rest) => {
+            map.get_key_value(&format!("*.{}", rest))
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(names: &[&str]) -> HashMap<String, String> {
+        names.iter().map(|name| (name.to_string(), name.to_string())).collect()
+    }
+
+    #[test]
+    fn valid_wildcards() {
+        assert!(is_valid_wildcard("*.example.com"));
+        assert!(is_valid_wildcard("*.a.b.example.com"));
+
+        assert!(!is_valid_wildcard("*.com"));
+        assert!(!is_valid_wildcard("*."));
+        assert!(!is_valid_wildcard("*"));
+        assert!(!is_valid_wildcard("a*.example.com"));
+        assert!(!is_valid_wildcard("*.*.example.com"));
+        assert!(!is_valid_wildcard("*.example..com"));
+        assert!(!is_valid_wildcard("example.com"));
+    }
+
+    #[test]
+    fn wildcard_matches_one_label() {
+        assert!(matches("*.example.com", "api.example.com"));
+
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", "a.b.example.com"));
+        assert!(!matches("*.example.com", ".example.com"));
+        assert!(!matches("*.com", "example.com"));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert!(matches("Example.COM", "example.com."));
+        assert!(matches("*.EXAMPLE.com", "API.example.COM"));
+
+        let map = map(&["example.com", "*.example.com"]);
+
+        assert_eq!(lookup(&map, "EXAMPLE.com.").unwrap(), "example.com");
+        assert_eq!(lookup(&map, "API.Example.com").unwrap(), "*.example.com");
+    }
+
+    #[test]
+    fn exact_name_wins() {
+        let map = map(&["api.example.com", "*.example.com"]);
+
+        assert_eq!(lookup(&map, "api.example.com").unwrap(), "api.example.com");
+        assert_eq!(lookup(&map, "www.example.com").unwrap(), "*.example.com");
+    }
+
+    #[test]
+    fn lookup_one_label() {
+        let map = map(&["*.example.com", "*.com"]);
+
+        assert_eq!(lookup_entry(&map, "www.example.com").unwrap().0, "*.example.com");
+        assert!(lookup(&map, "example.com").is_none());
+        assert!(lookup(&map, "a.b.example.com").is_none());
+    }
+}
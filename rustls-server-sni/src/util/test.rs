@@ -1,8 +1,14 @@
 use crate::AnyError;
 
-use rustls::{ResolvesServerCert, ClientHello};
+use super::names::{self, normalize, is_valid_wildcard};
 
+use rustls::{ResolvesServerCert, ClientHello, SignatureScheme};
+use rustls::internal::msgs::enums::SignatureAlgorithm;
+
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::RwLock;
 
 use rustls::sign::CertifiedKey;
 
@@ -12,31 +18,259 @@ use rustls::sign::CertifiedKey;
 // Should be replaced with rustls::ResolvesServerCertUsingSNI
 // in production code
 pub struct ResolvesServerCertUsingSNI {
-    map: HashMap<String, CertifiedKey>,
+    // Names can change while server is running.
+    domains: RwLock<Domains>,
+}
+
+tokio::task_local! {
+    // Key chosen by a handshake running in `record_selected`.
+    static SELECTED: RefCell<Option<CertifiedKey>>;
+}
+
+#[derive(Default)]
+struct Domains {
+    // Keys of every name, most preferred first.
+    map: HashMap<String, Vec<CertifiedKey>>,
+    // Name used when client sends no name or an unknown one.
+    default: Option<String>,
 }
 
 impl ResolvesServerCertUsingSNI {
     pub fn new() -> Self {
         Self {
-            map: HashMap::new(),
+            domains: RwLock::new(Domains::default()),
         }
     }
 
-    pub fn add(&mut self, name: &str, ck: CertifiedKey) -> Result<(), AnyError> {
-        self.map.insert(name.to_owned(), ck);
+    /// Serve `name` to clients without a known name. Fails if there is a default already.
+    pub fn set_default(&self, name: &str) -> Result<(), AnyError> {
+        let mut domains = self.domains.write().unwrap();
+
+        if domains.default.is_some() {
+            return Err("default certificate is already set".into());
+        }
+
+        domains.default = Some(normalize(name));
+
+        Ok(())
+    }
+
+    /// Whether a certificate is served for `sni`, `None` when client sent no name.
+    pub fn resolves(&self, sni: Option<&str>) -> bool {
+        self.domains.read().unwrap().get(sni).is_some()
+    }
+
+    /// Serve one of `keys` for `name`, chosen by signature schemes client supports.
+    /// Keys of `name` are replaced if it is served already.
+    ///
+    /// A leftmost `*` label matches exactly one label, so "*.example.com"
+    /// matches "api.example.com" but not "example.com".
+    pub fn add(&self, name: &str, keys: Vec<CertifiedKey>) -> Result<(), AnyError> {
+        let name = normalize(name);
+
+        if name.contains('*') && !is_valid_wildcard(&name) {
+            return Err(format!("invalid wildcard name {:?}", name).into());
+        }
+
+        if keys.is_empty() {
+            return Err(format!("no certificate for {:?}", name).into());
+        }
+
+        self.domains.write().unwrap().map.insert(name, by_preference(keys));
 
         Ok(())
     }
+
+    /// Stop serving `name`. Returns false if it wasn't served.
+    pub fn remove(&self, name: &str) -> bool {
+        self.domains.write().unwrap().map.remove(&normalize(name)).is_some()
+    }
+
+    /// Keys served to a client that sent `sni`.
+    pub fn keys(&self, sni: Option<&str>) -> Option<Vec<CertifiedKey>> {
+        self.domains.read().unwrap().get(sni).cloned()
+    }
+
+    /// Name, or wildcard, whose keys are served for `name`. `None` when only default would be.
+    pub fn served_name(&self, name: &str) -> Option<String> {
+        names::lookup_entry(&self.domains.read().unwrap().map, name).map(|(name, _)| name.clone())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.domains.read().unwrap().map.contains_key(&normalize(name))
+    }
+
+    /// Served names with their keys, sorted by name.
+    pub fn list(&self) -> Vec<(String, Vec<CertifiedKey>)> {
+        let domains = self.domains.read().unwrap();
+
+        let mut list: Vec<_> = domains.map.iter()
+            .map(|(name, keys)| (name.clone(), keys.clone()))
+            .collect();
+
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+
+        list
+    }
+
+    pub fn default_name(&self) -> Option<String> {
+        self.domains.read().unwrap().default.clone()
+    }
+}
+
+impl Domains {
+    // Keys of `sni`, or of default domain when there are none.
+    fn get(&self, sni: Option<&str>) -> Option<&Vec<CertifiedKey>> {
+        sni.and_then(|sni| self.lookup(sni))
+            .or_else(|| self.default.as_ref().and_then(|name| self.map.get(name)))
+    }
+
+    fn lookup(&self, sni: &str) -> Option<&Vec<CertifiedKey>> {
+        names::lookup(&self.map, sni)
+    }
 }
 
 impl ResolvesServerCert for ResolvesServerCertUsingSNI {
     fn resolve(&self, client_hello: ClientHello<'_>) -> Option<CertifiedKey> {
-        let sni: String = match client_hello.server_name() {
-            // some sort of magic (couldn't find a better way)
-            Some(sni) => AsRef::<str>::as_ref(&sni.to_owned()).to_owned(),
-            None => return None,
+        // some sort of magic (couldn't find a better way)
+        let sni = client_hello.server_name().map(|sni| AsRef::<str>::as_ref(&sni.to_owned()).to_owned());
+
+        let domains = self.domains.read().unwrap();
+        let keys = domains.get(sni.as_deref())?;
+
+        let key = choose(keys, client_hello.sigschemes()).cloned();
+
+        let _ = SELECTED.try_with(|selected| *selected.borrow_mut() = key.clone());
+
+        key
+    }
+}
+
+/// Run handshake `fut`, also returning key the resolver chose for it.
+///
+/// Key is `None` when none was chosen, like for a resumed session.
+pub async fn record_selected<F: Future>(fut: F) -> (F::Output, Option<CertifiedKey>) {
+    SELECTED.scope(RefCell::new(None), async {
+        let output = fut.await;
+        let key = SELECTED.with(|selected| selected.borrow_mut().take());
+
+        (output, key)
+    }).await
+}
+
+// ECDSA and Ed25519 handshakes are cheaper, so RSA is only for clients that need it.
+// Order of config is kept otherwise.
+fn by_preference(mut keys: Vec<CertifiedKey>) -> Vec<CertifiedKey> {
+    keys.sort_by_key(|ck| ck.key.algorithm() == SignatureAlgorithm::RSA);
+    keys
+}
+
+// First key that can sign with a scheme client offered. If none can,
+// handshake fails anyway, first key is given to let rustls report it.
+fn choose<'a>(keys: &'a [CertifiedKey], sigschemes: &[SignatureScheme]) -> Option<&'a CertifiedKey> {
+    keys.iter()
+        .find(|ck| ck.key.choose_scheme(sigschemes).is_some())
+        .or_else(|| keys.first())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::{certified_key, key_type, Source};
+
+    // Key of certs/test fixture `name`, or of example certificate for "rsa".
+    fn key(name: &str) -> CertifiedKey {
+        let (key, cert) = match name {
+            "rsa" => ("certs/key.pem".to_owned(), "certs/cert.pem".to_owned()),
+            _ => (format!("certs/test/{}.key.pem", name), format!("certs/test/{}.cert.pem", name)),
         };
 
-        self.map.get(&sni).cloned()
+        certified_key(name, &Source::parse(&key), &Source::parse(&cert)).unwrap()
+    }
+
+    fn served(resolver: &ResolvesServerCertUsingSNI, sni: Option<&str>) -> Option<String> {
+        let keys = resolver.keys(sni)?;
+
+        Some(format!("{:?}", keys[0].key.algorithm()))
+    }
+
+    #[test]
+    fn no_default() {
+        let resolver = ResolvesServerCertUsingSNI::new();
+
+        resolver.add("localhost", vec![key("rsa")]).unwrap();
+
+        assert!(resolver.resolves(Some("localhost")));
+        assert!(resolver.resolves(Some("LOCALHOST.")));
+        assert!(!resolver.resolves(Some("unknown")));
+        assert!(!resolver.resolves(None));
+        assert_eq!(resolver.default_name(), None);
+    }
+
+    #[test]
+    fn default_fallback() {
+        let resolver = ResolvesServerCertUsingSNI::new();
+
+        resolver.add("localhost", vec![key("rsa")]).unwrap();
+        resolver.add("localtest", vec![key("ecdsa-p256")]).unwrap();
+        resolver.set_default("Localtest").unwrap();
+
+        assert_eq!(served(&resolver, Some("localhost")).unwrap(), "RSA");
+        assert_eq!(served(&resolver, Some("unknown")).unwrap(), "ECDSA");
+        assert_eq!(served(&resolver, None).unwrap(), "ECDSA");
+
+        // Only names served for themselves, not through default.
+        assert_eq!(resolver.served_name("unknown"), None);
+        assert_eq!(resolver.default_name().unwrap(), "localtest");
+
+        assert!(resolver.set_default("localhost").is_err());
+    }
+
+    // Default can be removed by admin api, then nothing is served for unknown names.
+    #[test]
+    fn default_removed() {
+        let resolver = ResolvesServerCertUsingSNI::new();
+
+        resolver.add("localhost", vec![key("rsa")]).unwrap();
+        resolver.set_default("localhost").unwrap();
+
+        assert!(resolver.remove("localhost"));
+        assert!(!resolver.resolves(None));
+        assert!(!resolver.resolves(Some("unknown")));
+    }
+
+    fn types(keys: &[CertifiedKey]) -> Vec<&'static str> {
+        keys.iter().map(key_type).collect()
+    }
+
+    #[test]
+    fn rsa_last() {
+        let keys = by_preference(vec![key("rsa"), key("ed25519"), key("ecdsa-p256")]);
+
+        // Config order is kept otherwise.
+        assert_eq!(types(&keys), ["Ed25519", "ECDSA P-256", "RSA"]);
+    }
+
+    #[test]
+    fn choose_by_client_schemes() {
+        let keys = by_preference(vec![key("rsa"), key("ecdsa-p256")]);
+
+        let both = [SignatureScheme::RSA_PSS_SHA256, SignatureScheme::ECDSA_NISTP256_SHA256];
+        let rsa_only = [SignatureScheme::RSA_PSS_SHA256, SignatureScheme::RSA_PKCS1_SHA256];
+
+        assert_eq!(key_type(choose(&keys, &both).unwrap()), "ECDSA P-256");
+        assert_eq!(key_type(choose(&keys, &rsa_only).unwrap()), "RSA");
+    }
+
+    // Handshake fails later anyway, rustls gets first key to report it.
+    #[test]
+    fn choose_without_match() {
+        let keys = by_preference(vec![key("rsa"), key("ecdsa-p384")]);
+
+        let key = choose(&keys, &[SignatureScheme::ED448]).unwrap();
+
+        assert_eq!(key_type(key), "ECDSA P-384");
+        assert!(choose(&[], &[SignatureScheme::ED448]).is_none());
     }
 }
@@ -0,0 +1,488 @@
+use crate::AnyError;
+use middleware::{BoxBody, X_REQUEST_ID};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+
+use rustls::{ServerSession, Session};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use tracing::Span;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Common,
+    #[default]
+    Combined,
+    // Combined with request id appended.
+    #[serde(rename = "combined_id")]
+    CombinedId,
+    Json,
+}
+
+#[derive(Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    format: Format,
+    // Logs go to stdout when no path is given.
+    path: Option<String>,
+}
+
+/// Per connection data that is logged with every request.
+pub struct ConnInfo {
+    peer: SocketAddr,
+    tls: bool,
+    sni: Option<String>,
+    alpn: Option<String>,
+}
+
+impl ConnInfo {
+    pub fn new(peer: SocketAddr, session: &ServerSession) -> Self {
+        Self {
+            peer,
+            tls: true,
+            sni: session.get_sni_hostname().map(str::to_owned),
+            alpn: session.get_alpn_protocol()
+                .map(|p| String::from_utf8_lossy(p).into_owned()),
+        }
+    }
+
+    pub fn from_parts(
+        peer: SocketAddr,
+        tls: bool,
+        sni: Option<String>,
+        alpn: Option<String>,
+    ) -> Self {
+        Self { peer, tls, sni, alpn }
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Fill "sni" and "alpn" fields of `span`.
+    pub fn record(&self, span: &Span) {
+        if let Some(sni) = &self.sni {
+            span.record("sni", sni.as_str());
+        }
+
+        if let Some(alpn) = &self.alpn {
+            span.record("alpn", alpn.as_str());
+        }
+    }
+
+    /// Uri scheme requests on this connection were made with.
+    pub fn scheme(&self) -> &'static str {
+        match self.tls {
+            true => "https",
+            false => "http",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLog {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    format: Format,
+    path: Option<String>,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<Self, AnyError> {
+        let writer = match &config.path {
+            Some(path) => Box::new(open_file(path)?) as Box<dyn Write + Send>,
+            None => Box::new(io::stdout()),
+        };
+
+        let inner = Inner {
+            format: config.format,
+            path: config.path.clone(),
+            writer: Mutex::new(writer),
+        };
+
+        Ok(Self { inner: Arc::new(inner) })
+    }
+
+    /// Reopen log file. Used after logrotate moved the old one away.
+    pub fn reopen(&self) -> Result<(), AnyError> {
+        if let Some(path) = &self.inner.path {
+            let file = open_file(path)?;
+
+            *self.inner.writer.lock().unwrap() = Box::new(file);
+        }
+
+        Ok(())
+    }
+
+    /// Spawn a task that reopens log file every time SIGHUP is received.
+    pub fn reopen_on_sighup(&self) -> Result<(), AnyError> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let log = self.clone();
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                if let Err(e) = log.reopen() {
+                    println!("error reopening access log: {}", e);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Start recording a request. Finish it with [`Record::finish`].
+    pub fn record(&self, req: &Request<Body>, conn: &Arc<ConnInfo>) -> Record {
+        let target = req.uri().path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/")
+            .to_owned();
+
+        Record {
+            log: self.clone(),
+            conn: conn.clone(),
+            start: Instant::now(),
+            time: Utc::now(),
+            method: req.method().to_string(),
+            target,
+            version: format!("{:?}", req.version()),
+            referer: header_string(req.headers(), "referer"),
+            user_agent: header_string(req.headers(), "user-agent"),
+            status: StatusCode::OK,
+            request_id: None,
+        }
+    }
+
+    fn write(&self, record: &Record, bytes: u64) {
+        let line = match self.inner.format {
+            Format::Common => common_line(record, bytes),
+            Format::Combined => combined_line(record, bytes),
+            Format::CombinedId => combined_id_line(record, bytes),
+            Format::Json => json_line(record, bytes),
+        };
+
+        let mut writer = self.inner.writer.lock().unwrap();
+
+        let _ = writer.write_all(line.as_bytes());
+        let _ = writer.flush();
+    }
+}
+
+pub struct Record {
+    log: AccessLog,
+    conn: Arc<ConnInfo>,
+    start: Instant,
+    time: DateTime<Utc>,
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    status: StatusCode,
+    request_id: Option<String>,
+}
+
+impl Record {
+    /// Wrap response body so the entry is written once body is sent.
+    pub fn finish(mut self, resp: Response<BoxBody>) -> Response<LoggedBody> {
+        self.status = resp.status();
+        // Echoed by middleware, so it's there even when request had none.
+        self.request_id = header_string(resp.headers(), X_REQUEST_ID.as_str());
+
+        resp.map(|inner| LoggedBody {
+            inner,
+            bytes: 0,
+            record: Some(self),
+        })
+    }
+}
+
+/// Response body that counts sent bytes and writes access log entry on drop.
+pub struct LoggedBody {
+    inner: BoxBody,
+    bytes: u64,
+    record: Option<Record>,
+}
+
+impl From<BoxBody> for LoggedBody {
+    fn from(inner: BoxBody) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            record: None,
+        }
+    }
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = AnyError;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+
+        if let Poll::Ready(Some(Ok(data))) = &poll {
+            self.bytes += data.len() as u64;
+        }
+
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            record.log.write(&record, self.bytes);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: String,
+    peer: String,
+    method: &'a str,
+    path: &'a str,
+    version: &'a str,
+    status: u16,
+    bytes: u64,
+    duration_ms: f64,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    sni: Option<&'a str>,
+    alpn: Option<&'a str>,
+    request_id: Option<&'a str>,
+}
+
+fn common_line(record: &Record, bytes: u64) -> String {
+    format!("{}\n", request_line(record, bytes))
+}
+
+fn combined_line(record: &Record, bytes: u64) -> String {
+    format!("{}\n", combined_fields(record, bytes))
+}
+
+// Request id as an extra quoted field, like a LogFormat ending in "%{X-Request-Id}o" would write it.
+fn combined_id_line(record: &Record, bytes: u64) -> String {
+    format!(
+        "{} \"{}\"\n",
+        combined_fields(record, bytes),
+        escape(record.request_id.as_deref().unwrap_or("-")),
+    )
+}
+
+fn combined_fields(record: &Record, bytes: u64) -> String {
+    format!(
+        "{} \"{}\" \"{}\"",
+        request_line(record, bytes),
+        escape(record.referer.as_deref().unwrap_or("-")),
+        escape(record.user_agent.as_deref().unwrap_or("-")),
+    )
+}
+
+// Fields shared by common and combined formats.
+fn request_line(record: &Record, bytes: u64) -> String {
+    let bytes = match bytes {
+        0 => "-".to_owned(),
+        n => n.to_string(),
+    };
+
+    format!(
+        "{} - - [{}] \"{} {} {}\" {} {}",
+        record.conn.peer.ip(),
+        record.time.format("%d/%b/%Y:%H:%M:%S %z"),
+        record.method,
+        escape(&record.target),
+        record.version,
+        record.status.as_u16(),
+        bytes,
+    )
+}
+
+fn json_line(record: &Record, bytes: u64) -> String {
+    let entry = JsonEntry {
+        time: record.time.to_rfc3339(),
+        peer: record.conn.peer.to_string(),
+        method: &record.method,
+        path: &record.target,
+        version: &record.version,
+        status: record.status.as_u16(),
+        bytes,
+        duration_ms: record.start.elapsed().as_secs_f64() * 1000.0,
+        referer: record.referer.as_deref(),
+        user_agent: record.user_agent.as_deref(),
+        sni: record.conn.sni.as_deref(),
+        alpn: record.conn.alpn.as_deref(),
+        request_id: record.request_id.as_deref(),
+    };
+
+    let mut line = serde_json::to_string(&entry).unwrap();
+    line.push('\n');
+
+    line
+}
+
+// Quotes and backslashes would break quoted fields in CLF.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn header_string(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+}
+
+fn open_file(path: &str) -> Result<File, AnyError> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Ok(file),
+        Err(_) => Err(format!("Can't open {:?}.", path).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    fn record() -> Record {
+        let log = AccessLog::new(&AccessLogConfig { format: Format::Combined, path: None }).unwrap();
+        let conn = Arc::new(ConnInfo::from_parts("127.0.0.1:4000".parse().unwrap(), true, None, None));
+
+        let req = Request::get("/index.html?q=1")
+            .header("referer", "https://example.com/")
+            .header("user-agent", "curl/7.88.1")
+            .body(Body::empty())
+            .unwrap();
+
+        let mut record = log.record(&req, &conn);
+
+        record.time = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        record.request_id = Some("abc-123".to_owned());
+
+        record
+    }
+
+    #[test]
+    fn standard_formats_without_request_id() {
+        let record = record();
+
+        assert_eq!(
+            common_line(&record, 42),
+            "127.0.0.1 - - [01/Jan/2021:00:00:00 +0000] \"GET /index.html?q=1 HTTP/1.1\" 200 42\n",
+        );
+        assert_eq!(
+            combined_line(&record, 42),
+            "127.0.0.1 - - [01/Jan/2021:00:00:00 +0000] \"GET /index.html?q=1 HTTP/1.1\" 200 42 \
+             \"https://example.com/\" \"curl/7.88.1\"\n",
+        );
+    }
+
+    #[test]
+    fn missing_fields_are_dashes() {
+        let mut record = record();
+
+        record.status = StatusCode::NOT_FOUND;
+        record.referer = None;
+        record.user_agent = None;
+
+        assert_eq!(
+            common_line(&record, 0),
+            "127.0.0.1 - - [01/Jan/2021:00:00:00 +0000] \"GET /index.html?q=1 HTTP/1.1\" 404 -\n",
+        );
+        assert!(combined_line(&record, 0).ends_with("404 - \"-\" \"-\"\n"));
+    }
+
+    #[test]
+    fn escape_quotes_and_backslashes() {
+        assert_eq!(escape(r#"say "hi" \ bye"#), r#"say \"hi\" \\ bye"#);
+        assert_eq!(escape("plain"), "plain");
+
+        let mut record = record();
+
+        record.user_agent = Some(r#"evil" "agent\"#.to_owned());
+
+        assert!(combined_line(&record, 42).ends_with(" 42 \"https://example.com/\" \"evil\\\" \\\"agent\\\\\"\n"));
+    }
+
+    #[test]
+    fn combined_id_appends_request_id() {
+        let mut record = record();
+
+        assert_eq!(
+            combined_id_line(&record, 42),
+            "127.0.0.1 - - [01/Jan/2021:00:00:00 +0000] \"GET /index.html?q=1 HTTP/1.1\" 200 42 \
+             \"https://example.com/\" \"curl/7.88.1\" \"abc-123\"\n",
+        );
+
+        record.request_id = None;
+
+        assert!(combined_id_line(&record, 42).ends_with("\"curl/7.88.1\" \"-\"\n"));
+    }
+
+    #[test]
+    fn json_has_request_id() {
+        let line = json_line(&record(), 42);
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(entry["request_id"], "abc-123");
+    }
+
+    #[test]
+    fn json_fields() {
+        let mut record = record();
+
+        record.user_agent = Some(r#"evil" "agent\"#.to_owned());
+
+        let line = json_line(&record, 42);
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert!(line.ends_with('\n'));
+        assert_eq!(entry["time"], "2021-01-01T00:00:00+00:00");
+        assert_eq!(entry["peer"], "127.0.0.1:4000");
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["path"], "/index.html?q=1");
+        assert_eq!(entry["version"], "HTTP/1.1");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["bytes"], 42);
+        assert_eq!(entry["referer"], "https://example.com/");
+        // Json has its own escaping, value comes back unchanged.
+        assert_eq!(entry["user_agent"], r#"evil" "agent\"#);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::AnyError;
+use crate::access_log::ConnInfo;
+use crate::listener;
+
+use std::future::Future;
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt};
+
+use serde::Deserialize;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+// Negotiating these hands connection to hyper.
+const HTTP_PROTOCOLS: [&str; 2] = ["h2", "http/1.1"];
+
+#[derive(Deserialize)]
+pub struct AlpnConfig {
+    // Connections that negotiate "protocol" are piped to "upstream" as is.
+    #[serde(default)]
+    forward: Vec<ForwardConfig>,
+}
+
+#[derive(Deserialize)]
+struct ForwardConfig {
+    protocol: String,
+    upstream: String,
+}
+
+/// TLS stream of a connection, on either rustls version.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+type Handler = Arc<dyn Fn(Box<dyn Io>, Arc<ConnInfo>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Non-http protocols served on TLS port, chosen by negotiated ALPN identifier.
+#[derive(Clone, Default)]
+pub struct Protocols {
+    // Registration order is preference order.
+    handlers: Vec<(Vec<u8>, Handler)>,
+}
+
+impl Protocols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Protocols that forward connections to upstreams listed in `config`.
+    pub fn from_config(config: &AlpnConfig) -> Result<Self, AnyError> {
+        let mut protocols = Self::new();
+
+        for forward in &config.forward {
+            let upstream = forward.upstream.clone();
+
+            protocols = protocols.add(&forward.protocol, move |stream, _conn| {
+                pipe(stream, upstream.clone())
+            })?;
+        }
+
+        Ok(protocols)
+    }
+
+    /// Serve connections that negotiate `id` with `handler`.
+    pub fn add<F, Fut>(mut self, id: &str, handler: F) -> Result<Self, AnyError>
+    where
+        F: Fn(Box<dyn Io>, Arc<ConnInfo>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        if id.is_empty() || id.len() > 255 {
+            return Err(format!("Invalid ALPN protocol {:?}.", id).into());
+        }
+
+        if HTTP_PROTOCOLS.contains(&id) {
+            return Err(format!("ALPN protocol {:?} is served by http.", id).into());
+        }
+
+        if self.get(id.as_bytes()).is_some() {
+            return Err(format!("ALPN protocol {:?} is registered twice.", id).into());
+        }
+
+        let handler: Handler = Arc::new(move |stream, conn| handler(stream, conn).boxed());
+
+        self.handlers.push((id.as_bytes().to_vec(), handler));
+
+        Ok(self)
+    }
+
+    /// Identifiers to advertise next to http ones.
+    pub fn ids(&self) -> Vec<Vec<u8>> {
+        self.handlers.iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Handler of negotiated protocol, `None` for http.
+    pub fn get(&self, alpn: &[u8]) -> Option<&Handler> {
+        self.handlers.iter()
+            .find(|(id, _)| id == alpn)
+            .map(|(_, handler)| handler)
+    }
+}
+
+async fn pipe(mut stream: Box<dyn Io>, upstream: String) {
+    let res = async {
+        let addr = listener::resolve(&upstream).await?;
+        let mut upstream = TcpStream::connect(addr).await?;
+
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+
+        Ok::<_, AnyError>(())
+    };
+
+    if let Err(e) = res.await {
+        println!("error forwarding alpn connection to {}: {}", upstream, e);
+    }
+}
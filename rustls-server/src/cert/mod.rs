@@ -0,0 +1,120 @@
+mod newer;
+
+pub use newer::NewerCert;
+
+use crate::AnyError;
+use crate::health::Health;
+use crate::util::{certified_key, load_key_pair, KeyPair};
+
+use std::sync::RwLock;
+use std::sync::Arc;
+
+use rustls::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Certificate resolver that can swap certificate while server is running.
+pub struct ReloadableCert {
+    key_location: String,
+    cert_location: String,
+    hostnames: Vec<String>,
+    current: RwLock<Loaded>,
+}
+
+#[derive(Clone)]
+struct Loaded {
+    key: CertifiedKey,
+    pair: Arc<KeyPair>,
+    // Unix timestamp of leaf certificate expiry.
+    not_after: i64,
+}
+
+impl ReloadableCert {
+    /// Load and check certificate chain and key. `hostnames` must be covered by leaf.
+    pub fn load(
+        key_location: &str,
+        cert_location: &str,
+        hostnames: &[String],
+    ) -> Result<Self, AnyError> {
+        let loaded = load(key_location, cert_location, hostnames)?;
+
+        Ok(Self {
+            key_location: key_location.to_owned(),
+            cert_location: cert_location.to_owned(),
+            hostnames: hostnames.to_vec(),
+            current: RwLock::new(loaded),
+        })
+    }
+
+    /// Load certificate and key again. Previous ones are kept on error.
+    pub fn reload(&self) -> Result<(), AnyError> {
+        let loaded = load(&self.key_location, &self.cert_location, &self.hostnames)?;
+
+        *self.current.write().unwrap() = loaded;
+
+        Ok(())
+    }
+
+    /// Chain and key currently in use. A new `Arc` is returned after every reload.
+    pub fn key_pair(&self) -> Arc<KeyPair> {
+        self.current.read().unwrap().pair.clone()
+    }
+
+    pub fn not_after(&self) -> i64 {
+        self.current.read().unwrap().not_after
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().key.clone())
+    }
+}
+
+/// Spawn a task that reloads certificate every time SIGHUP is received.
+pub fn reload_on_sighup(cert: Arc<ReloadableCert>, health: Health) -> Result<(), AnyError> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let result = cert.reload().map_err(|e| e.to_string());
+
+            match &result {
+                Ok(()) => println!("certificate reloaded"),
+                Err(e) => println!("error reloading certificate: {}", e),
+            }
+
+            health.set_reload_result(result);
+        }
+    });
+
+    Ok(())
+}
+
+fn load(
+    key_location: &str,
+    cert_location: &str,
+    hostnames: &[String],
+) -> Result<Loaded, AnyError> {
+    let pair = load_key_pair(key_location, cert_location)?;
+    let key = certified_key(&pair)?;
+
+    let hostnames: Vec<&str> = hostnames.iter().map(String::as_str).collect();
+    let problems = cert_check::check_key(&key, &hostnames);
+
+    if !problems.is_empty() {
+        return Err(problems.join(" ").into());
+    }
+
+    let (_, leaf) = x509_parser::parse_x509_certificate(&key.cert[0].0)
+        .map_err(|_| "Can't parse leaf certificate.")?;
+
+    let not_after = leaf.validity().not_after.timestamp();
+
+    Ok(Loaded {
+        key,
+        pair: Arc::new(pair),
+        not_after,
+    })
+}
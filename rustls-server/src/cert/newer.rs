@@ -0,0 +1,61 @@
+use crate::cert::ReloadableCert;
+use crate::util::KeyPair;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use rustls_quic::crypto::ring;
+use rustls_quic::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls_quic::server::{ClientHello, ResolvesServerCert};
+use rustls_quic::sign::CertifiedKey;
+
+/// Serves certificate of [`ReloadableCert`] to the newer rustls of QUIC and early data.
+pub struct NewerCert {
+    cert: Arc<ReloadableCert>,
+    // Converted key is reused until certificate is reloaded.
+    cached: Mutex<Option<(Arc<KeyPair>, Arc<CertifiedKey>)>>,
+}
+
+impl NewerCert {
+    pub fn new(cert: Arc<ReloadableCert>) -> Self {
+        Self {
+            cert,
+            cached: Mutex::new(None),
+        }
+    }
+
+    fn convert(pair: &KeyPair) -> Option<Arc<CertifiedKey>> {
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(pair.key.0.clone()));
+        let signing_key = ring::sign::any_supported_type(&key).ok()?;
+
+        let certs = pair.certs.iter()
+            .map(|cert| CertificateDer::from(cert.0.clone()))
+            .collect();
+
+        Some(Arc::new(CertifiedKey::new(certs, signing_key)))
+    }
+}
+
+impl ResolvesServerCert for NewerCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let pair = self.cert.key_pair();
+        let mut cached = self.cached.lock().unwrap();
+
+        if let Some((cached_pair, key)) = &*cached {
+            if Arc::ptr_eq(cached_pair, &pair) {
+                return Some(key.clone());
+            }
+        }
+
+        let key = Self::convert(&pair)?;
+        *cached = Some((pair, key.clone()));
+
+        Some(key)
+    }
+}
+
+impl fmt::Debug for NewerCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewerCert").finish_non_exhaustive()
+    }
+}
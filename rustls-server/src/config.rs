@@ -0,0 +1,94 @@
+use crate::AnyError;
+use crate::access_log::AccessLogConfig;
+use crate::alpn::AlpnConfig;
+use crate::early_data::EarlyDataConfig;
+use crate::health::HealthConfig;
+use crate::http3::Http3Config;
+use middleware::MiddlewareConfig;
+use crate::proxy::ProxyConfig;
+
+use std::fs::File;
+use std::io::BufReader;
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "default_addr")]
+    pub addr: String,
+    // Listeners bound to "addr" with SO_REUSEPORT, each accepting in its own task.
+    #[serde(default = "default_accept_shards")]
+    pub accept_shards: usize,
+    // Serve cleartext http/1.1 and http/2 instead of TLS.
+    #[serde(default)]
+    pub h2c: bool,
+    // Required unless h2c is enabled.
+    pub key_location: Option<String>,
+    pub cert_location: Option<String>,
+    // Names that certificate must be valid for.
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    // Access logging is disabled when this is missing.
+    pub access_log: Option<AccessLogConfig>,
+    // Requests are forwarded to upstream servers when this is present.
+    pub proxy: Option<ProxyConfig>,
+    // Http3 over QUIC is served next to tcp listener when this is present.
+    pub http3: Option<Http3Config>,
+    // TLS 1.3 early data (0-RTT) is accepted on tcp and QUIC when this is present.
+    pub early_data: Option<EarlyDataConfig>,
+    // Non-http protocols negotiated with ALPN on the same port.
+    pub alpn: Option<AlpnConfig>,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub middleware: MiddlewareConfig,
+    // Spans are exported over OTLP when this is present.
+    pub telemetry: Option<TelemetryConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct TelemetryConfig {
+    // OTLP/HTTP traces url of collector, like "http://127.0.0.1:4318/v1/traces".
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Self, AnyError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Err(format!("Can't open {:?}.", path).into()),
+        };
+
+        let config: Self = serde_json::from_reader(BufReader::new(file))?;
+
+        if config.accept_shards == 0 {
+            return Err("accept_shards must be at least 1.".into());
+        }
+
+        config.middleware.validate()?;
+
+        if let Some(proxy) = &config.proxy {
+            proxy.validate()?;
+        }
+
+        if let Some(early_data) = &config.early_data {
+            early_data.validate()?;
+        }
+
+        Ok(config)
+    }
+}
+
+fn default_addr() -> String {
+    "127.0.0.1:3443".to_owned()
+}
+
+fn default_accept_shards() -> usize {
+    1
+}
+
+fn default_service_name() -> String {
+    "rustls-server".to_owned()
+}
@@ -0,0 +1,91 @@
+use crate::AnyError;
+
+use hyper::{Body, Request, Response};
+use hyper::header::HeaderValue;
+
+use serde::Deserialize;
+
+const EARLY_DATA: &str = "early-data";
+
+// Early data is buffered per connection until it is read.
+const MAX_EARLY_DATA_SIZE: u32 = 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct EarlyDataConfig {
+    // Bytes of early data accepted on a tcp connection. QUIC limits it with flow control.
+    #[serde(default = "default_max_early_data_size")]
+    pub max_early_data_size: u32,
+}
+
+impl EarlyDataConfig {
+    pub fn validate(&self) -> Result<(), AnyError> {
+        if self.max_early_data_size == 0 || self.max_early_data_size > MAX_EARLY_DATA_SIZE {
+            return Err(format!(
+                "early_data.max_early_data_size must be between 1 and {}.",
+                MAX_EARLY_DATA_SIZE,
+            ).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Request extension set on requests that may have arrived in TLS 1.3 early data, fully or
+/// partly. See [`mark`].
+///
+/// Early data can be replayed by an attacker. Handlers that aren't idempotent should answer
+/// these requests with [`too_early`] so client retries them after handshake.
+#[derive(Clone, Copy)]
+pub struct EarlyData;
+
+/// Mark `req` as received in early data, with [`EarlyData`] extension and "early-data: 1"
+/// header, so handlers and upstreams can tell.
+// SEE: https://datatracker.ietf.org/doc/html/rfc8470#section-5.1
+pub fn mark<B>(req: &mut Request<B>) {
+    req.extensions_mut().insert(EarlyData);
+    req.headers_mut().insert(EARLY_DATA, HeaderValue::from_static("1"));
+}
+
+/// Whether `req` was received in early data, here or by a proxy in front that says so.
+pub fn is_early<B>(req: &Request<B>) -> bool {
+    req.extensions().get::<EarlyData>().is_some()
+        || req.headers().get(EARLY_DATA).is_some_and(|v| v == "1")
+}
+
+// SEE: https://datatracker.ietf.org/doc/html/rfc8470#section-5.2
+pub fn too_early() -> Response<Body> {
+    Response::builder()
+        .status(425)
+        .header("content-type", "text/plain")
+        .body(Body::from("Too Early"))
+        .unwrap()
+}
+
+// A full TLS record.
+fn default_max_early_data_size() -> u32 {
+    16384
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marked_requests_are_early() {
+        let mut req = Request::new(());
+
+        assert!(!is_early(&req));
+
+        mark(&mut req);
+
+        assert!(is_early(&req));
+        assert_eq!(req.headers()[EARLY_DATA], "1");
+    }
+
+    #[test]
+    fn header_of_proxy_in_front_is_early() {
+        let req = Request::get("/").header("early-data", "1").body(()).unwrap();
+
+        assert!(is_early(&req));
+    }
+}
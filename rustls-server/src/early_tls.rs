@@ -0,0 +1,476 @@
+use crate::AnyError;
+use crate::access_log::ConnInfo;
+use crate::cert::{NewerCert, ReloadableCert};
+use crate::early_data::EarlyDataConfig;
+use crate::util;
+
+use std::future::poll_fn;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::ready;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use rustls_quic::ServerConnection;
+use rustls_quic::crypto::ring;
+
+/// Accepts TLS on the newer rustls, which reads early data before handshake completes.
+///
+/// rustls 0.19 of the tcp listener only implements early data for QUIC.
+#[derive(Clone)]
+pub struct Acceptor {
+    config: Arc<rustls_quic::ServerConfig>,
+}
+
+/// TLS stream that gives early data first, while handshake is still running.
+pub struct TlsStream<IO> {
+    io: IO,
+    conn: ServerConnection,
+    early_reads: Arc<EarlyReads>,
+}
+
+/// Tells whether a request read from a [`TlsStream`] may have arrived in early data.
+///
+/// A request can start in early data and end after handshake. So once early data is read,
+/// requests are flagged until the first one taken after client sent more than early data.
+#[derive(Default)]
+pub struct EarlyReads {
+    inner: Mutex<Reads>,
+}
+
+#[derive(Default)]
+struct Reads {
+    // Early data was read, requests may still start in it.
+    early: bool,
+    // Data sent after early data was read.
+    late: bool,
+}
+
+impl Acceptor {
+    /// Serve certificate of `cert` and negotiate `protocols` before http ones.
+    pub fn new(
+        cert: Arc<ReloadableCert>,
+        protocols: &[Vec<u8>],
+        early_data: &EarlyDataConfig,
+    ) -> Result<Self, AnyError> {
+        let mut config = rustls_quic::ServerConfig::builder_with_provider(
+            Arc::new(ring::default_provider())
+        )
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NewerCert::new(cert)));
+
+        config.alpn_protocols = util::alpn_protocols(protocols);
+        config.max_early_data_size = early_data.max_early_data_size;
+        // Responses to early requests are sent right after server's handshake messages,
+        // so client gets them a round trip sooner.
+        config.send_half_rtt_data = true;
+
+        Ok(Self { config: Arc::new(config) })
+    }
+
+    /// Run handshake until it completes, or until early data is accepted.
+    pub async fn accept<IO>(&self, io: IO) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let conn = ServerConnection::new(self.config.clone())
+            .map_err(io::Error::other)?;
+
+        let mut stream = TlsStream {
+            io,
+            conn,
+            early_reads: Arc::new(EarlyReads::default()),
+        };
+
+        poll_fn(|cx| stream.poll_accept(cx)).await?;
+
+        Ok(stream)
+    }
+}
+
+impl<IO> TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn early_reads(&self) -> Arc<EarlyReads> {
+        self.early_reads.clone()
+    }
+
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.conn.alpn_protocol()
+    }
+
+    pub fn conn_info(&self, peer: SocketAddr) -> ConnInfo {
+        ConnInfo::from_parts(
+            peer,
+            true,
+            self.conn.server_name().map(str::to_owned),
+            self.conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).into_owned()),
+        )
+    }
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            // Server's handshake messages go out before early data is read.
+            while self.conn.wants_write() {
+                ready!(self.write_io(cx))?;
+            }
+
+            ready!(Pin::new(&mut self.io).poll_flush(cx))?;
+
+            if !self.conn.is_handshaking() || self.conn.early_data().is_some() {
+                return Poll::Ready(Ok(()));
+            }
+
+            if ready!(self.read_io(cx))? == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    // Read TLS records from `io` and process them.
+    fn read_io(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let n = match self.conn.read_tls(&mut SyncIo { io: &mut self.io, cx }) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+
+        if let Err(e) = self.conn.process_new_packets() {
+            // Alert that tells client why is sent on a best effort basis.
+            let _ = self.write_io(cx);
+
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    // Write pending TLS records to `io`.
+    fn write_io(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        match self.conn.write_tls(&mut SyncIo { io: &mut self.io, cx }) {
+            Ok(0) => Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+            Ok(n) => Poll::Ready(Ok(n)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.wants_write() {
+            ready!(self.write_io(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO> AsyncRead for TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            // Early data comes before anything client sent after handshake.
+            if let Some(mut early) = this.conn.early_data() {
+                let n = early.read(buf.initialize_unfilled())?;
+
+                if n > 0 {
+                    buf.advance(n);
+                    this.early_reads.read_early();
+
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                // Zero is a clean close by client.
+                Ok(n) => {
+                    buf.advance(n);
+                    this.early_reads.read_late();
+
+                    return Poll::Ready(Ok(()));
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            // Handshake messages and tickets are sent while reading.
+            if let Poll::Ready(Err(e)) = this.poll_write_tls(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            ready!(this.read_io(cx))?;
+        }
+    }
+}
+
+impl<IO> AsyncWrite for TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut written = 0;
+
+        while written < buf.len() {
+            let n = this.conn.writer().write(&buf[written..])?;
+
+            written += n;
+
+            match this.poll_write_tls(cx) {
+                Poll::Ready(Ok(())) if n > 0 => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                // Nothing written yet, wait until io can take more.
+                Poll::Pending if written == 0 => return Poll::Pending,
+                _ => break,
+            }
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        this.conn.writer().flush()?;
+        ready!(this.poll_write_tls(cx))?;
+
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        // Sending it again is a no-op for rustls.
+        this.conn.send_close_notify();
+        ready!(this.poll_write_tls(cx))?;
+
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+impl EarlyReads {
+    /// Whether request that is taken now may have arrived in early data, fully or partly.
+    pub fn take_request(&self) -> bool {
+        let mut reads = self.inner.lock().unwrap();
+        let early = reads.early;
+
+        // Later requests are read after this one, so they can't start in early data.
+        if reads.late {
+            reads.early = false;
+        }
+
+        early
+    }
+
+    fn read_early(&self) {
+        self.inner.lock().unwrap().early = true;
+    }
+
+    fn read_late(&self) {
+        let mut reads = self.inner.lock().unwrap();
+
+        reads.late = reads.early;
+    }
+}
+
+// Blocking io interface rustls reads and writes TLS records with, pending is `WouldBlock`.
+struct SyncIo<'a, 'b, IO> {
+    io: &'a mut IO,
+    cx: &'a mut Context<'b>,
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Read for SyncIo<'_, '_, IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> Write for SyncIo<'_, '_, IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, cert};
+
+    use std::convert::TryFrom;
+    use std::net::TcpStream;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use rustls_quic::{ClientConfig, ClientConnection};
+    use rustls_quic::pki_types::ServerName;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+
+    fn client_config(cert: &ReloadableCert) -> Arc<ClientConfig> {
+        let mut config = test_util::pinned_client(cert);
+
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        config.enable_early_data = true;
+
+        Arc::new(config)
+    }
+
+    // Sends `count` requests. First `early_len` bytes of first one go in early data when session
+    // allows it. Returns whether early data was accepted.
+    fn requests(config: &Arc<ClientConfig>, port: u16, early_len: usize, count: usize) -> bool {
+        let mut conn = ClientConnection::new(config.clone(), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut sock = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+        let early = match conn.early_data() {
+            Some(mut early) => {
+                early.write_all(&REQUEST[..early_len]).unwrap();
+                true
+            },
+            None => false,
+        };
+
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock).unwrap();
+        }
+
+        let accepted = early && conn.is_early_data_accepted();
+        let sent = if accepted { early_len } else { 0 };
+
+        let mut tls = rustls_quic::Stream::new(&mut conn, &mut sock);
+
+        for i in 0..count {
+            let request = if i == 0 { &REQUEST[sent..] } else { REQUEST };
+            tls.write_all(request).unwrap();
+
+            let mut response = vec![0; RESPONSE.len()];
+            tls.read_exact(&mut response).unwrap();
+
+            assert_eq!(response, RESPONSE);
+        }
+
+        accepted
+    }
+
+    // Serves connections, sending whether every request may have arrived in early data.
+    async fn serve(listener: TcpListener, acceptor: Acceptor, results: std::sync::mpsc::Sender<bool>) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let results = results.clone();
+
+            tokio::spawn(async move {
+                let mut request = vec![0; REQUEST.len()];
+
+                // Client closes without close_notify, ending with an error.
+                while stream.read_exact(&mut request).await.is_ok() {
+                    assert_eq!(request, REQUEST);
+
+                    results.send(stream.early_reads().take_request()).unwrap();
+
+                    stream.write_all(RESPONSE).await.unwrap();
+                    stream.flush().await.unwrap();
+                }
+            });
+        }
+    }
+
+    fn start() -> (Arc<ClientConfig>, u16, std::sync::mpsc::Receiver<bool>) {
+        let cert = cert();
+        let config = EarlyDataConfig { max_early_data_size: 16384 };
+        let acceptor = Acceptor::new(cert.clone(), &[], &config).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        listener.set_nonblocking(true).unwrap();
+        tokio::spawn(serve(TcpListener::from_std(listener).unwrap(), acceptor, tx));
+
+        (client_config(&cert), port, rx)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn early_data() {
+        let (client, port, results) = start();
+
+        let (accepted, early) = tokio::task::spawn_blocking(move || {
+            // First one gets a session ticket, second one resumes it.
+            assert!(!requests(&client, port, REQUEST.len(), 1));
+            assert!(!results.recv().unwrap());
+
+            (requests(&client, port, REQUEST.len(), 1), results.recv().unwrap())
+        }).await.unwrap();
+
+        assert!(accepted);
+        assert!(early);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn request_split_by_handshake() {
+        let (client, port, results) = start();
+
+        let (accepted, early) = tokio::task::spawn_blocking(move || {
+            assert!(!requests(&client, port, REQUEST.len(), 1));
+            assert!(!results.recv().unwrap());
+
+            // Request line in early data, headers after handshake. A second request follows.
+            let accepted = requests(&client, port, 16, 2);
+
+            (accepted, [results.recv().unwrap(), results.recv().unwrap()])
+        }).await.unwrap();
+
+        assert!(accepted);
+        assert_eq!(early, [true, false]);
+    }
+
+    #[test]
+    fn requests_flagged_until_taken_after_late_data() {
+        let reads = EarlyReads::default();
+
+        reads.read_late();
+        assert!(!reads.take_request());
+
+        reads.read_early();
+        assert!(reads.take_request());
+
+        // Next request may have started in early data that was read already.
+        reads.read_late();
+        assert!(reads.take_request());
+        assert!(!reads.take_request());
+    }
+}
@@ -0,0 +1,582 @@
+use crate::{serve_connection, State};
+use crate::access_log::{ConnInfo, LoggedBody};
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::{Body, HeaderMap, Request, Response, StatusCode, Version};
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::header::{HeaderName, CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING, UPGRADE};
+use hyper::http::request::Parts;
+use hyper::server::conn::Http;
+use hyper::upgrade::{OnUpgrade, Upgraded};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+// SEE: https://datatracker.ietf.org/doc/html/rfc7540#section-3.5
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Bodies up to initial flow control window can be replayed on the new connection.
+// Bigger ones are answered over http/1.1 without upgrading.
+const MAX_UPGRADE_BODY: u64 = 65_535;
+
+// Default SETTINGS_MAX_FRAME_SIZE.
+const MAX_FRAME_SIZE: usize = 16_384;
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+// Every SETTINGS parameter is a 16 bit identifier and a 32 bit value.
+const SETTING_LEN: usize = 6;
+
+const CONNECTION_STREAM_ID: u32 = 0;
+
+// Upgraded request always becomes stream 1.
+// SEE: https://datatracker.ietf.org/doc/html/rfc7540#section-3.2
+const UPGRADE_STREAM_ID: u32 = 1;
+
+/// Whether `req` asks for `Upgrade: h2c` and can be replayed over http/2.
+pub fn can_upgrade(req: &Request<Body>) -> bool {
+    let headers = req.headers();
+
+    req.version() == Version::HTTP_11
+        && has_token(headers, &UPGRADE, "h2c")
+        && has_token(headers, &CONNECTION, "upgrade")
+        && upgrade_settings(headers).is_some()
+        && !headers.contains_key(TRANSFER_ENCODING)
+        && content_length(headers) <= MAX_UPGRADE_BODY
+}
+
+/// Answer with "101 Switching Protocols" and serve `req` as first stream of a new http/2 connection.
+pub async fn upgrade(
+    mut req: Request<Body>,
+    state: State,
+    conn: Arc<ConnInfo>,
+) -> Response<LoggedBody> {
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let (parts, body) = req.into_parts();
+    let settings = upgrade_settings(&parts.headers).unwrap_or_default();
+
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => return empty_response(StatusCode::BAD_REQUEST),
+    };
+
+    let frames = request_frames(&parts, &body);
+
+    tokio::spawn(serve_upgraded(on_upgrade, settings, frames, state, conn));
+
+    let mut resp = empty_response(StatusCode::SWITCHING_PROTOCOLS);
+
+    resp.headers_mut().insert(CONNECTION, "Upgrade".parse().unwrap());
+    resp.headers_mut().insert(UPGRADE, "h2c".parse().unwrap());
+
+    resp
+}
+
+// Boxed because serve_connection calls upgrade, which would make future type recursive.
+fn serve_upgraded(
+    on_upgrade: OnUpgrade,
+    settings: Vec<u8>,
+    frames: Vec<u8>,
+    state: State,
+    conn: Arc<ConnInfo>,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        let mut io = match on_upgrade.await {
+            Ok(io) => io,
+            Err(_) => return,
+        };
+
+        let prefix = match read_client_start(&mut io, &settings, frames).await {
+            Ok(prefix) => prefix,
+            Err(_) => return,
+        };
+
+        let io = Prefixed {
+            prefix: Bytes::from(prefix),
+            io,
+        };
+
+        let mut http = Http::new();
+        http.http2_only(true);
+
+        serve_connection(io, conn, None, state, http).await;
+    })
+}
+
+// Client sends preface and SETTINGS first, upgraded request frames must come after them.
+//
+// Settings of "HTTP2-Settings" header are put in front of ones in client's SETTINGS frame,
+// so they apply first and client's own values override them. Server acknowledges that
+// single frame, an extra acknowledgement for the header would be unexpected by client.
+// SEE: https://datatracker.ietf.org/doc/html/rfc7540#section-3.2.1
+async fn read_client_start<I>(io: &mut I, upgrade_settings: &[u8], frames: Vec<u8>) -> io::Result<Vec<u8>>
+where
+    I: AsyncRead + Unpin,
+{
+    let mut start = vec![0; PREFACE.len() + 9];
+    io.read_exact(&mut start).await?;
+
+    let header = &start[PREFACE.len()..];
+
+    if &start[..PREFACE.len()] != PREFACE || header[3] != FRAME_SETTINGS || header[4] != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid http/2 preface"));
+    }
+
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+
+    let mut settings = upgrade_settings.to_vec();
+    settings.resize(upgrade_settings.len() + len, 0);
+    io.read_exact(&mut settings[upgrade_settings.len()..]).await?;
+
+    let mut prefix = PREFACE.to_vec();
+
+    write_frame(&mut prefix, FRAME_SETTINGS, 0, CONNECTION_STREAM_ID, &settings);
+    prefix.extend(frames);
+
+    Ok(prefix)
+}
+
+// Payload of a SETTINGS frame, sent base64url encoded without padding.
+// SEE: https://datatracker.ietf.org/doc/html/rfc7540#section-3.2.1
+fn upgrade_settings(headers: &HeaderMap) -> Option<Vec<u8>> {
+    let mut values = headers.get_all("http2-settings").iter();
+
+    let value = match (values.next(), values.next()) {
+        (Some(value), None) => value.to_str().ok()?,
+        _ => return None,
+    };
+
+    let settings = base64::decode_config(value.trim().trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()?;
+
+    match settings.len() % SETTING_LEN {
+        0 => Some(settings),
+        _ => None,
+    }
+}
+
+fn request_frames(parts: &Parts, body: &[u8]) -> Vec<u8> {
+    let block = header_block(parts);
+    let mut frames = Vec::new();
+
+    let blocks: Vec<&[u8]> = block.chunks(MAX_FRAME_SIZE).collect();
+
+    for (i, chunk) in blocks.iter().enumerate() {
+        let (kind, mut flags) = match i {
+            0 => (FRAME_HEADERS, 0),
+            _ => (FRAME_CONTINUATION, 0),
+        };
+
+        if i == 0 && body.is_empty() {
+            flags |= FLAG_END_STREAM;
+        }
+
+        if i == blocks.len() - 1 {
+            flags |= FLAG_END_HEADERS;
+        }
+
+        write_frame(&mut frames, kind, flags, UPGRADE_STREAM_ID, chunk);
+    }
+
+    let chunks: Vec<&[u8]> = body.chunks(MAX_FRAME_SIZE).collect();
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let flags = match i == chunks.len() - 1 {
+            true => FLAG_END_STREAM,
+            false => 0,
+        };
+
+        write_frame(&mut frames, FRAME_DATA, flags, UPGRADE_STREAM_ID, chunk);
+    }
+
+    frames
+}
+
+fn write_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    out.extend(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend(&stream_id.to_be_bytes());
+    out.extend(payload);
+}
+
+// Every field is a literal without indexing, so no HPACK state is shared with client.
+// SEE: https://datatracker.ietf.org/doc/html/rfc7541#section-6.2.2
+fn header_block(parts: &Parts) -> Vec<u8> {
+    let mut block = Vec::new();
+
+    let path = parts.uri.path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    write_field(&mut block, b":method", parts.method.as_str().as_bytes());
+    write_field(&mut block, b":scheme", b"http");
+    write_field(&mut block, b":path", path.as_bytes());
+
+    if let Some(host) = parts.headers.get(HOST) {
+        write_field(&mut block, b":authority", host.as_bytes());
+    }
+
+    for (name, value) in &parts.headers {
+        if is_connection_header(name) || (name == "te" && value != "trailers") {
+            continue;
+        }
+
+        write_field(&mut block, name.as_str().as_bytes(), value.as_bytes());
+    }
+
+    block
+}
+
+fn write_field(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    write_string(block, name);
+    write_string(block, value);
+}
+
+fn write_string(block: &mut Vec<u8>, s: &[u8]) {
+    // Huffman bit is left unset.
+    write_int(block, s.len(), 7);
+    block.extend(s);
+}
+
+// SEE: https://datatracker.ietf.org/doc/html/rfc7541#section-5.1
+fn write_int(block: &mut Vec<u8>, value: usize, prefix_bits: u32) {
+    let max = (1 << prefix_bits) - 1;
+
+    if value < max {
+        block.push(value as u8);
+        return;
+    }
+
+    block.push(max as u8);
+
+    let mut rest = value - max;
+
+    while rest >= 128 {
+        block.push((rest % 128) as u8 | 0x80);
+        rest /= 128;
+    }
+
+    block.push(rest as u8);
+}
+
+// SEE: https://datatracker.ietf.org/doc/html/rfc7540#section-8.1.2.2
+fn is_connection_header(name: &HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection" | "host" | "http2-settings" | "keep-alive" | "proxy-connection"
+            | "transfer-encoding" | "upgrade"
+    )
+}
+
+fn has_token(headers: &HeaderMap, name: &HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn content_length(headers: &HeaderMap) -> u64 {
+    headers.get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+fn empty_response(status: StatusCode) -> Response<LoggedBody> {
+    let body = Body::empty().map_err(Into::into).boxed_unsync();
+
+    let mut resp = Response::new(LoggedBody::from(body));
+    *resp.status_mut() = status;
+
+    resp
+}
+
+/// Upgraded connection that yields `prefix` before reading from `io`.
+struct Prefixed {
+    prefix: Bytes,
+    io: Upgraded,
+}
+
+impl AsyncRead for Prefixed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.prefix.has_remaining() {
+            let len = self.prefix.len().min(buf.remaining());
+
+            buf.put_slice(&self.prefix[..len]);
+            self.prefix.advance(len);
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Prefixed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::Method;
+
+    use tokio::io::AsyncWriteExt;
+
+    fn parts(method: Method, uri: &str, headers: &[(&str, &str)]) -> Parts {
+        let mut builder = Request::builder().method(method).uri(uri);
+
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn int(value: usize, prefix_bits: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_int(&mut out, value, prefix_bits);
+        out
+    }
+
+    fn decode(block: &[u8]) -> Vec<(String, String)> {
+        hpack::Decoder::new().decode(block).unwrap()
+            .into_iter()
+            .map(|(n, v)| (String::from_utf8(n).unwrap(), String::from_utf8(v).unwrap()))
+            .collect()
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    // SEE: https://datatracker.ietf.org/doc/html/rfc7541#appendix-C.1
+    #[test]
+    fn integers() {
+        assert_eq!(int(10, 5), [10]);
+        assert_eq!(int(1337, 5), [31, 154, 10]);
+        assert_eq!(int(42, 8), [42]);
+        assert_eq!(int(31, 5), [31, 0]);
+        assert_eq!(int(126, 7), [126]);
+        assert_eq!(int(127, 7), [127, 0]);
+        assert_eq!(int(128, 7), [127, 1]);
+        assert_eq!(int(255, 7), [127, 128, 1]);
+        assert_eq!(int(20_000, 7), [127, 161, 155, 1]);
+    }
+
+    #[test]
+    fn header_block_decodes() {
+        let long = "x".repeat(300);
+        let huge = "y".repeat(20_000);
+
+        let parts = parts(Method::POST, "/a/b?c=d", &[
+            ("host", "example.com:3080"),
+            ("connection", "Upgrade, HTTP2-Settings"),
+            ("upgrade", "h2c"),
+            ("http2-settings", "AAMAAABkAAQCAAAAAAIAAAAA"),
+            ("te", "gzip"),
+            ("x-long", &long),
+            ("x-huge", &huge),
+        ]);
+
+        assert_eq!(decode(&header_block(&parts)), vec![
+            pair(":method", "POST"),
+            pair(":scheme", "http"),
+            pair(":path", "/a/b?c=d"),
+            pair(":authority", "example.com:3080"),
+            pair("x-long", &long),
+            pair("x-huge", &huge),
+        ]);
+    }
+
+    #[test]
+    fn te_trailers_is_kept() {
+        let parts = parts(Method::GET, "/", &[("te", "trailers")]);
+
+        assert_eq!(decode(&header_block(&parts)), vec![
+            pair(":method", "GET"),
+            pair(":scheme", "http"),
+            pair(":path", "/"),
+            pair("te", "trailers"),
+        ]);
+    }
+
+    // Frame type and flags of every frame in `frames`.
+    fn frame_kinds(mut frames: &[u8]) -> Vec<(u8, u8, usize)> {
+        let mut kinds = Vec::new();
+
+        while !frames.is_empty() {
+            let len = u32::from_be_bytes([0, frames[0], frames[1], frames[2]]) as usize;
+
+            assert_eq!(frames[5..9], UPGRADE_STREAM_ID.to_be_bytes());
+
+            kinds.push((frames[3], frames[4], len));
+            frames = &frames[9 + len..];
+        }
+
+        kinds
+    }
+
+    #[test]
+    fn frames_split_at_max_frame_size() {
+        let huge = "y".repeat(MAX_FRAME_SIZE * 2);
+        let parts = parts(Method::POST, "/", &[("x-huge", &huge)]);
+        let body = vec![b'z'; MAX_FRAME_SIZE + 1];
+
+        let kinds = frame_kinds(&request_frames(&parts, &body));
+
+        assert_eq!(kinds.len(), 5);
+        assert_eq!(kinds[0], (FRAME_HEADERS, 0, MAX_FRAME_SIZE));
+        assert_eq!(kinds[1], (FRAME_CONTINUATION, 0, MAX_FRAME_SIZE));
+        assert_eq!((kinds[2].0, kinds[2].1), (FRAME_CONTINUATION, FLAG_END_HEADERS));
+        assert_eq!(kinds[3], (FRAME_DATA, 0, MAX_FRAME_SIZE));
+        assert_eq!(kinds[4], (FRAME_DATA, FLAG_END_STREAM, 1));
+    }
+
+    #[test]
+    fn frames_without_body_end_stream() {
+        let parts = parts(Method::GET, "/", &[]);
+
+        let kinds = frame_kinds(&request_frames(&parts, &[]));
+
+        assert_eq!(kinds.len(), 1);
+        assert_eq!((kinds[0].0, kinds[0].1), (FRAME_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS));
+    }
+
+    // Frames are read by an independent http/2 implementation.
+    async fn receive(upgrade_settings: &[u8], frames: Vec<u8>) -> (Request<h2::RecvStream>, Vec<u8>) {
+        let (mut client, server) = tokio::io::duplex(1024 * 1024);
+
+        let mut client_start = PREFACE.to_vec();
+        write_frame(&mut client_start, FRAME_SETTINGS, 0, CONNECTION_STREAM_ID, &[]);
+
+        let prefix = read_client_start(&mut &client_start[..], upgrade_settings, frames).await.unwrap();
+
+        client.write_all(&prefix).await.unwrap();
+
+        let mut conn = h2::server::handshake(server).await.unwrap();
+        let (req, _respond) = conn.accept().await.unwrap().unwrap();
+
+        tokio::spawn(async move { while conn.accept().await.is_some() {} });
+
+        let (parts, mut body) = req.into_parts();
+        let mut data = Vec::new();
+
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+
+            body.flow_control().release_capacity(chunk.len()).unwrap();
+            data.extend(chunk);
+        }
+
+        (Request::from_parts(parts, body), data)
+    }
+
+    #[tokio::test]
+    async fn request_decodes() {
+        let huge = "y".repeat(MAX_FRAME_SIZE * 2);
+        let body = vec![b'z'; MAX_FRAME_SIZE * 3];
+
+        let parts = parts(Method::POST, "/upload?x=1", &[
+            ("host", "localhost"),
+            ("upgrade", "h2c"),
+            ("x-huge", &huge),
+        ]);
+
+        let (req, data) = receive(&[], request_frames(&parts, &body)).await;
+
+        assert_eq!(req.method(), Method::POST);
+        assert_eq!(req.uri(), "http://localhost/upload?x=1");
+        assert_eq!(req.headers()["x-huge"], huge.as_str());
+        assert!(!req.headers().contains_key(UPGRADE));
+        assert_eq!(data, body);
+    }
+
+    #[tokio::test]
+    async fn client_start_merges_upgrade_settings() {
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100, then client's SETTINGS_ENABLE_PUSH = 0.
+        let upgrade_settings = [0, 3, 0, 0, 0, 100];
+        let client_settings = [0, 2, 0, 0, 0, 0];
+
+        let mut client_start = PREFACE.to_vec();
+        write_frame(&mut client_start, FRAME_SETTINGS, 0, CONNECTION_STREAM_ID, &client_settings);
+
+        let prefix = read_client_start(&mut &client_start[..], &upgrade_settings, b"rest".to_vec()).await.unwrap();
+
+        let mut expected = PREFACE.to_vec();
+        write_frame(&mut expected, FRAME_SETTINGS, 0, CONNECTION_STREAM_ID, &[upgrade_settings, client_settings].concat());
+        expected.extend(b"rest");
+
+        assert_eq!(prefix, expected);
+
+        // Still a valid start of a connection.
+        let parts = parts(Method::GET, "/", &[("host", "localhost")]);
+        let (req, _) = receive(&upgrade_settings, request_frames(&parts, &[])).await;
+
+        assert_eq!(req.uri().path(), "/");
+    }
+
+    #[tokio::test]
+    async fn client_start_needs_settings() {
+        let mut client_start = PREFACE.to_vec();
+        write_frame(&mut client_start, FRAME_DATA, 0, UPGRADE_STREAM_ID, &[]);
+
+        assert!(read_client_start(&mut &client_start[..], &[], Vec::new()).await.is_err());
+    }
+
+    #[test]
+    fn upgrade_settings_are_base64url() {
+        let headers = |values: &[&str]| {
+            let mut headers = HeaderMap::new();
+
+            for value in values {
+                headers.append("http2-settings", value.parse().unwrap());
+            }
+
+            headers
+        };
+
+        // curl's header.
+        assert_eq!(
+            upgrade_settings(&headers(&["AAMAAABkAAQCAAAAAAIAAAAA"])),
+            Some(vec![0, 3, 0, 0, 0, 100, 0, 4, 2, 0, 0, 0, 0, 2, 0, 0, 0, 0]),
+        );
+        assert_eq!(upgrade_settings(&headers(&[""])), Some(vec![]));
+        assert_eq!(upgrade_settings(&headers(&["________"])), Some(vec![255; 6]));
+
+        assert_eq!(upgrade_settings(&headers(&[])), None);
+        assert_eq!(upgrade_settings(&headers(&["AAMAAABk", "AAMAAABk"])), None);
+        assert_eq!(upgrade_settings(&headers(&["AAMAAA"])), None);
+        assert_eq!(upgrade_settings(&headers(&["not base64!"])), None);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::cert::ReloadableCert;
+use crate::proxy::Proxy;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use chrono::{TimeZone, Utc};
+
+use hyper::{Body, Response};
+
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+pub struct HealthConfig {
+    // Server isn't ready when certificate expires within this window.
+    #[serde(default = "default_expiry_window")]
+    expiry_window_days: i64,
+    // Time between shutdown signal and closing listener.
+    #[serde(default = "default_drain")]
+    pub drain_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            expiry_window_days: default_expiry_window(),
+            drain_secs: default_drain(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Health {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // Unset until certificate is loaded, `None` when serving cleartext h2c.
+    cert: OnceLock<Option<Arc<ReloadableCert>>>,
+    // Upstreams of proxy mode.
+    proxy: Option<Arc<Proxy>>,
+    expiry_window_secs: i64,
+    draining: AtomicBool,
+    reload_error: Mutex<Option<String>>,
+}
+
+impl Health {
+    /// Server isn't ready until [`set_cert`](Self::set_cert) is called, nor in proxy mode until
+    /// an upstream passed its health check.
+    pub fn new(proxy: Option<Arc<Proxy>>, config: &HealthConfig) -> Self {
+        let inner = Inner {
+            cert: OnceLock::new(),
+            proxy,
+            expiry_window_secs: config.expiry_window_days * 24 * 60 * 60,
+            draining: AtomicBool::new(false),
+            reload_error: Mutex::new(None),
+        };
+
+        Self { inner: Arc::new(inner) }
+    }
+
+    /// Set certificate once it is loaded, `None` when serving cleartext h2c.
+    pub fn set_cert(&self, cert: Option<Arc<ReloadableCert>>) {
+        let _ = self.inner.cert.set(cert);
+    }
+
+    pub fn set_draining(&self) {
+        self.inner.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_reload_result(&self, result: Result<(), String>) {
+        *self.inner.reload_error.lock().unwrap() = result.err();
+    }
+
+    /// Response for `/healthz`. Process is alive if it can answer.
+    pub fn healthz(&self) -> Response<Body> {
+        json_response(200, json!({ "status": "ok" }))
+    }
+
+    /// Response for `/readyz`.
+    pub fn readyz(&self) -> Response<Body> {
+        let (cert_ok, certificate) = match self.inner.cert.get() {
+            Some(Some(cert)) => self.check_cert(cert),
+            Some(None) => (true, serde_json::Value::Null),
+            None => (false, json!({ "ok": false, "error": "certificate is not loaded yet" })),
+        };
+
+        let (upstreams_ok, upstreams) = match &self.inner.proxy {
+            Some(proxy) => check_upstreams(proxy),
+            None => (true, serde_json::Value::Null),
+        };
+
+        let draining = self.inner.draining.load(Ordering::Relaxed);
+        let reload_error = self.inner.reload_error.lock().unwrap().clone();
+
+        let ready = cert_ok && upstreams_ok && !draining && reload_error.is_none();
+
+        let body = json!({
+            "ready": ready,
+            "certificate": certificate,
+            "upstreams": upstreams,
+            "draining": {
+                "ok": !draining,
+            },
+            "reload": {
+                "ok": reload_error.is_none(),
+                "error": reload_error,
+            },
+        });
+
+        json_response(if ready { 200 } else { 503 }, body)
+    }
+
+    fn check_cert(&self, cert: &ReloadableCert) -> (bool, serde_json::Value) {
+        let now = Utc::now().timestamp();
+        let not_after = cert.not_after();
+        let expires_in = not_after - now;
+
+        let cert_error = if expires_in <= 0 {
+            Some("certificate has expired")
+        } else if expires_in <= self.inner.expiry_window_secs {
+            Some("certificate expires within configured window")
+        } else {
+            None
+        };
+
+        let details = json!({
+            "ok": cert_error.is_none(),
+            "not_after": Utc.timestamp_opt(not_after, 0).single().map(|t| t.to_rfc3339()),
+            "expires_in_secs": expires_in,
+            "error": cert_error,
+        });
+
+        (cert_error.is_none(), details)
+    }
+}
+
+fn check_upstreams(proxy: &Proxy) -> (bool, serde_json::Value) {
+    let (healthy, total) = proxy.upstream_health();
+
+    let details = json!({
+        "ok": healthy > 0,
+        "healthy": healthy,
+        "total": total,
+        "error": if healthy > 0 { None } else { Some("no upstream passed its health check") },
+    });
+
+    (healthy > 0, details)
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn default_expiry_window() -> i64 {
+    7
+}
+
+fn default_drain() -> u64 {
+    5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::cert;
+
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use hyper::Server;
+    use hyper::service::{make_service_fn, service_fn};
+
+    async fn readyz(health: &Health) -> (u16, serde_json::Value) {
+        let resp = health.readyz();
+        let status = resp.status().as_u16();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    // Proxy to an upstream that passes health checks.
+    fn proxy() -> Arc<Proxy> {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async { Ok::<_, Infallible>(hyper::Response::new(Body::empty())) }))
+        });
+
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        let config = serde_json::from_value(json!({
+            "upstreams": [addr.to_string()],
+            "health_check": { "interval_secs": 1 },
+        })).unwrap();
+
+        Arc::new(Proxy::new(&config).unwrap())
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_certificate_loaded() {
+        let health = Health::new(None, &HealthConfig::default());
+
+        let (status, body) = readyz(&health).await;
+        assert_eq!(status, 503);
+        assert_eq!(body["certificate"]["ok"], false);
+
+        health.set_cert(Some(cert()));
+
+        let (status, body) = readyz(&health).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(body["certificate"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_upstream_checked() {
+        let proxy = proxy();
+        let health = Health::new(Some(proxy.clone()), &HealthConfig::default());
+
+        health.set_cert(None);
+
+        let (status, body) = readyz(&health).await;
+        assert_eq!(status, 503);
+        assert_eq!(body["upstreams"]["ok"], false);
+
+        proxy.start_health_checks();
+
+        // First check runs right away.
+        for _ in 0..50 {
+            if readyz(&health).await.0 == 200 {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let (status, body) = readyz(&health).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(body["upstreams"]["healthy"], 1);
+    }
+
+    #[tokio::test]
+    async fn not_ready_after_shutdown_starts() {
+        let health = Health::new(None, &HealthConfig::default());
+
+        health.set_cert(Some(cert()));
+        assert_eq!(readyz(&health).await.0, 200);
+
+        health.set_draining();
+
+        let (status, body) = readyz(&health).await;
+        assert_eq!(status, 503);
+        assert_eq!(body["draining"]["ok"], false);
+    }
+}
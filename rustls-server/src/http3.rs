@@ -0,0 +1,422 @@
+use crate::{AnyError, State};
+use crate::access_log::ConnInfo;
+use crate::cert::{NewerCert, ReloadableCert};
+use crate::early_data;
+use crate::listener;
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use h3::server::RequestResolver;
+
+use hyper::{Body, HeaderMap, Request, Version};
+use hyper::body::{Buf, Bytes, HttpBody};
+use hyper::header::HeaderValue;
+
+use quinn::{Endpoint, Incoming};
+use quinn::crypto::rustls::{HandshakeData, QuicServerConfig};
+
+use rustls_quic::crypto::ring;
+
+use serde::Deserialize;
+
+use tracing::Instrument;
+use tracing::field::Empty;
+
+#[derive(Deserialize)]
+pub struct Http3Config {
+    // UDP address. Same as "addr" of tcp listener when missing.
+    addr: Option<String>,
+    // How long clients may remember that http3 is available.
+    #[serde(default = "default_max_age")]
+    max_age_secs: u64,
+}
+
+pub struct Http3 {
+    endpoint: Endpoint,
+    alt_svc: HeaderValue,
+    early_data: bool,
+}
+
+impl Http3 {
+    /// Bind udp socket. Certificate is shared with tcp listener and follows its reloads.
+    ///
+    /// Early data is accepted when `early_data` is set.
+    pub async fn bind(
+        config: &Http3Config,
+        tcp_addr: &str,
+        cert: Arc<ReloadableCert>,
+        early_data: bool,
+    ) -> Result<Self, AnyError> {
+        let addr = listener::resolve(config.addr.as_deref().unwrap_or(tcp_addr)).await?;
+
+        let crypto = quic_crypto(cert, early_data)?;
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+        let endpoint = Endpoint::server(server_config, addr)?;
+        let port = endpoint.local_addr()?.port();
+
+        let alt_svc = format!("h3=\":{}\"; ma={}", port, config.max_age_secs);
+
+        Ok(Self {
+            endpoint,
+            alt_svc: HeaderValue::from_str(&alt_svc)?,
+            early_data,
+        })
+    }
+
+    /// Value of "alt-svc" header that advertises this endpoint.
+    pub fn alt_svc(&self) -> HeaderValue {
+        self.alt_svc.clone()
+    }
+
+    /// Spawn a task that accepts connections until shutdown.
+    pub fn spawn(self, state: State) {
+        tokio::spawn(async move {
+            let mut shutdown = state.shutdown.clone();
+
+            loop {
+                tokio::select! {
+                    incoming = self.endpoint.accept() => match incoming {
+                        Some(incoming) => {
+                            let early_data = self.early_data;
+                            let span = tracing::info_span!("accept", peer = %incoming.remote_address());
+
+                            tokio::spawn(handle_connection(incoming, early_data, state.clone()).instrument(span));
+                        },
+                        None => break,
+                    },
+                    _ = shutdown.changed() => break,
+                }
+            }
+
+            drop(state);
+            self.endpoint.wait_idle().await;
+        });
+    }
+}
+
+async fn handle_connection(incoming: Incoming, early_data: bool, state: State) {
+    let mut connecting = match incoming.accept() {
+        Ok(connecting) => connecting,
+        Err(_) => return,
+    };
+
+    // Streams opened while this is set were received in early data.
+    let handshaking = Arc::new(AtomicBool::new(early_data));
+
+    let span = tracing::info_span!("tls_handshake", sni = Empty, alpn = Empty);
+
+    let handshake = async {
+        match early_data {
+            true => {
+                // Wait for ClientHello so sni and alpn are known.
+                connecting.handshake_data().await.ok()?;
+
+                let (conn, handshake_done) = connecting.into_0rtt().ok()?;
+                let handshaking = handshaking.clone();
+
+                tokio::spawn(async move {
+                    handshake_done.await;
+                    handshaking.store(false, Ordering::Release);
+                });
+
+                Some(conn)
+            },
+            false => connecting.await.ok(),
+        }
+    };
+
+    let conn = match handshake.instrument(span.clone()).await {
+        Some(conn) => conn,
+        None => return,
+    };
+
+    let info = Arc::new(conn_info(&conn));
+    info.record(&span);
+    let mut shutdown = state.shutdown.clone();
+
+    let mut h3_conn = match h3::server::Connection::new(h3_quinn::Connection::new(conn)).await {
+        Ok(h3_conn) => h3_conn,
+        Err(_) => return,
+    };
+
+    let mut requests = FuturesUnordered::new();
+    let mut closing = *shutdown.borrow();
+
+    loop {
+        tokio::select! {
+            res = h3_conn.accept(), if !closing => match res {
+                Ok(Some(resolver)) => {
+                    let early = handshaking.load(Ordering::Acquire);
+
+                    requests.push(handle_request(resolver, early, state.clone(), info.clone()));
+                },
+                // Client went away or sent GOAWAY.
+                _ => closing = true,
+            },
+            Some(_) = requests.next(), if !requests.is_empty() => (),
+            _ = shutdown.changed(), if !closing => {
+                // Sends GOAWAY. Requests accepted so far are still answered.
+                closing = true;
+
+                let _ = h3_conn.shutdown(0).await;
+            },
+            else => break,
+        }
+    }
+}
+
+async fn handle_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    early: bool,
+    state: State,
+    conn: Arc<ConnInfo>,
+) -> Result<(), AnyError> {
+    let (req, stream) = resolver.resolve_request().await?;
+    let (mut send, mut recv) = stream.split();
+    let (mut body_tx, body) = Body::channel();
+
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    let bytes = chunk.copy_to_bytes(chunk.remaining());
+
+                    if body_tx.send_data(bytes).await.is_err() {
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                // Stream was reset or connection lost. Body must not look complete.
+                Err(_) => {
+                    body_tx.abort();
+                    break;
+                },
+            }
+        }
+    });
+
+    let mut req = to_hyper_request(req, body)?;
+
+    if early {
+        early_data::mark(&mut req);
+    }
+
+    let resp = state.dispatch(req, &conn).await.unwrap_or_else(|e| match e {});
+
+    let (parts, mut body) = resp.into_parts();
+
+    let mut head = http1::Response::builder().status(parts.status.as_u16());
+
+    for (name, value) in parts.headers.iter().filter(|(name, _)| !is_connection_header(name)) {
+        head = head.header(name.as_str(), value.as_bytes());
+    }
+
+    send.send_response(head.body(())?).await?;
+
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk?).await?;
+    }
+
+    if let Some(trailers) = body.trailers().await? {
+        send.send_trailers(to_h3_headers(&trailers)?).await?;
+    }
+
+    send.finish().await?;
+
+    Ok(())
+}
+
+fn to_hyper_request(req: http1::Request<()>, body: Body) -> Result<Request<Body>, AnyError> {
+    let (parts, ()) = req.into_parts();
+
+    let mut builder = Request::builder()
+        .method(parts.method.as_str())
+        .uri(parts.uri.to_string())
+        .version(Version::HTTP_3);
+
+    for (name, value) in &parts.headers {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+
+    Ok(builder.body(body)?)
+}
+
+fn to_h3_headers(headers: &HeaderMap) -> Result<http1::HeaderMap, AnyError> {
+    let mut map = http1::HeaderMap::new();
+
+    for (name, value) in headers {
+        map.append(
+            http1::HeaderName::from_bytes(name.as_str().as_bytes())?,
+            http1::HeaderValue::from_bytes(value.as_bytes())?,
+        );
+    }
+
+    Ok(map)
+}
+
+// Http3 forbids connection specific headers.
+// SEE: https://www.rfc-editor.org/rfc/rfc9114.html#section-4.2
+fn is_connection_header(name: &hyper::header::HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+    )
+}
+
+fn conn_info(conn: &quinn::Connection) -> ConnInfo {
+    let handshake = conn.handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok());
+
+    let (sni, alpn) = match handshake {
+        Some(data) => (
+            data.server_name,
+            data.protocol.map(|p| String::from_utf8_lossy(&p).into_owned()),
+        ),
+        None => (None, None),
+    };
+
+    ConnInfo::from_parts(conn.remote_address(), true, sni, alpn)
+}
+
+fn quic_crypto(
+    cert: Arc<ReloadableCert>,
+    early_data: bool,
+) -> Result<QuicServerConfig, AnyError> {
+    let mut tls_config = rustls_quic::ServerConfig::builder_with_provider(
+        Arc::new(ring::default_provider())
+    )
+        .with_protocol_versions(&[&rustls_quic::version::TLS13])?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(NewerCert::new(cert)));
+
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+
+    if early_data {
+        // QUIC only allows 0 or this, "max_early_data_size" can't apply.
+        // Amount of early data is limited by flow control instead.
+        tls_config.max_early_data_size = u32::MAX;
+    }
+
+    Ok(QuicServerConfig::try_from(tls_config)?)
+}
+
+fn default_max_age() -> u64 {
+    86400
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, cert};
+
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::Response;
+    use hyper::service::service_fn;
+
+    use middleware::MiddlewareConfig;
+
+    use quinn::crypto::rustls::QuicClientConfig;
+
+    use tokio::sync::{mpsc, watch};
+
+    type SendRequest = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+    // Serves a service that echoes request bodies. Every read body is sent to returned receiver,
+    // after request arrival is sent as `None`.
+    async fn server() -> (SocketAddr, mpsc::UnboundedReceiver<Option<Result<Bytes, String>>>, watch::Sender<bool>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let service = service_fn(move |req: Request<Body>| {
+            let tx = tx.clone();
+
+            async move {
+                let _ = tx.send(None);
+
+                let body = hyper::body::to_bytes(req.into_body()).await.map_err(|e| e.to_string());
+                let _ = tx.send(Some(body.clone()));
+
+                Ok::<_, Infallible>(Response::new(Body::from(body.unwrap_or_default())))
+            }
+        });
+
+        let (state, shutdown) = test_util::state(middleware::stack(service, &MiddlewareConfig::default()).unwrap());
+
+        let config = Http3Config { addr: Some("127.0.0.1:0".to_owned()), max_age_secs: 60 };
+        let http3 = Http3::bind(&config, "", cert(), false).await.unwrap();
+        let addr = http3.endpoint.local_addr().unwrap();
+
+        http3.spawn(state);
+
+        (addr, rx, shutdown)
+    }
+
+    async fn client(addr: SocketAddr) -> SendRequest {
+        let mut tls = test_util::pinned_client(&cert());
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+
+        let quic = QuicClientConfig::try_from(tls).unwrap();
+
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(quic)));
+
+        let conn = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+        let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(conn)).await.unwrap();
+
+        tokio::spawn(async move { driver.wait_idle().await });
+
+        send_request
+    }
+
+    fn post() -> http1::Request<()> {
+        http1::Request::post("https://localhost/echo").body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (addr, mut bodies, _shutdown) = server().await;
+        let mut send_request = client(addr).await;
+
+        let mut stream = send_request.send_request(post()).await.unwrap();
+
+        stream.send_data(Bytes::from_static(b"hello, ")).await.unwrap();
+        stream.send_data(Bytes::from_static(b"world")).await.unwrap();
+        stream.finish().await.unwrap();
+
+        let resp = stream.recv_response().await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().contains_key("x-request-id"));
+
+        let mut body = Vec::new();
+
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+
+        assert_eq!(body, b"hello, world");
+        assert_eq!(bodies.recv().await.unwrap(), None);
+        assert_eq!(bodies.recv().await.unwrap(), Some(Ok(Bytes::from_static(b"hello, world"))));
+    }
+
+    #[tokio::test]
+    async fn reset_body_is_error() {
+        let (addr, mut bodies, _shutdown) = server().await;
+        let mut send_request = client(addr).await;
+
+        let mut stream = send_request.send_request(post()).await.unwrap();
+
+        stream.send_data(Bytes::from_static(b"partial")).await.unwrap();
+
+        // Reset after service got the request so it's reading the body.
+        assert_eq!(bodies.recv().await.unwrap(), None);
+        stream.stop_stream(h3::error::Code::H3_REQUEST_CANCELLED);
+
+        assert!(matches!(bodies.recv().await.unwrap(), Some(Err(_))));
+    }
+}
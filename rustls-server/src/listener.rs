@@ -0,0 +1,11 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::lookup_host;
+
+pub async fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    match lookup_host(addr).await?.next() {
+        Some(addr) => Ok(addr),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't resolve {:?}.", addr))),
+    }
+}
@@ -1,50 +1,541 @@
+mod access_log;
+mod alpn;
+mod cert;
+mod config;
+mod early_data;
+mod early_tls;
+mod h2c;
+mod health;
+mod http3;
+mod listener;
+mod proxy;
+#[cfg(test)]
+mod test_util;
 mod util;
 
+use access_log::{AccessLog, ConnInfo, LoggedBody};
+use alpn::Protocols;
+use cert::ReloadableCert;
+use config::Config;
+use early_tls::EarlyReads;
+use health::Health;
+use http3::Http3;
+use middleware::BoxService;
+use proxy::Proxy;
+
 use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use std::sync::Arc;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch};
 
 use tokio_rustls::TlsAcceptor;
 
+use futures::future::{Either, FutureExt};
+
 use hyper::{Body, Request, Response};
+use hyper::header::{HeaderValue, ALT_SVC};
 use hyper::service::service_fn;
 use hyper::server::conn::Http;
 
-type AnyError = Box<dyn std::error::Error + Send + Sync>;
+use router::Router;
+
+use rustls::Session;
+
+use tower::ServiceExt;
+
+use telemetry::Telemetry;
+
+use tracing::Instrument;
+use tracing::field::{display, Empty};
+
+pub type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone)]
+enum Acceptor {
+    Tls(TlsAcceptor),
+    // Newer rustls, used when early data is enabled.
+    EarlyData(early_tls::Acceptor),
+}
+
+#[derive(Clone)]
+struct State {
+    // Connections are cleartext h2c when missing.
+    acceptor: Option<Acceptor>,
+    // Handlers for connections that don't negotiate http.
+    protocols: Protocols,
+    access_log: Option<AccessLog>,
+    service: BoxService,
+    // Advertises http3 endpoint on tcp responses.
+    alt_svc: Option<HeaderValue>,
+    shutdown: watch::Receiver<bool>,
+    // Main task waits until every clone is dropped.
+    _done: mpsc::Sender<()>,
+}
+
+impl State {
+    /// Run request through access log and service stack.
+    fn dispatch(
+        &self,
+        mut req: Request<Body>,
+        conn: &Arc<ConnInfo>,
+    ) -> impl Future<Output = Result<Response<LoggedBody>, Infallible>> + Send + 'static {
+        let record = self.access_log.as_ref()
+            .map(|log| log.record(&req, conn));
+
+        req.extensions_mut().insert(conn.clone());
+
+        let fut = self.service.clone().oneshot(req);
+
+        async move {
+            let resp = fut.await?;
+
+            Ok(match record {
+                Some(record) => record.finish(resp),
+                None => resp.map(Into::into),
+            })
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), AnyError> {
-    let rustls_config = Arc::new(
-        util::rustls_server_config("certs/key.pem", "certs/cert.pem")?
-    );
+    let check_config = std::env::args().skip(1).any(|arg| arg == "--check-config");
 
-    let acceptor = TlsAcceptor::from(rustls_config);
-    let listener = TcpListener::bind("127.0.0.1:3443").await?;
+    let config = Config::from_file("config.json")?;
 
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let acceptor = acceptor.clone();
+    if check_config {
+        load_cert(&config)?;
+        println!("configuration is valid");
+
+        return Ok(());
+    }
+
+    let telemetry = match &config.telemetry {
+        Some(t) => Telemetry::init(&t.service_name, Some(&t.otlp_endpoint))?,
+        None => Telemetry::init("rustls-server", None)?,
+    };
+
+    let res = run(config).await;
+
+    // Send spans that are still buffered.
+    telemetry.shutdown();
+
+    res
+}
+
+async fn run(config: Config) -> Result<(), AnyError> {
+    let access_log = match &config.access_log {
+        Some(log_config) => {
+            let log = AccessLog::new(log_config)?;
+            log.reopen_on_sighup()?;
+
+            Some(log)
+        },
+        None => None,
+    };
+
+    let proxy = match &config.proxy {
+        Some(proxy_config) => {
+            let proxy = Proxy::new(proxy_config)?;
+            proxy.start_health_checks();
+
+            Some(Arc::new(proxy))
+        },
+        None => None,
+    };
+
+    let health = Health::new(proxy.clone(), &config.health);
+
+    let cert = load_cert(&config)?;
+    health.set_cert(cert.clone());
+
+    if let Some(cert) = &cert {
+        cert::reload_on_sighup(cert.clone(), health.clone())?;
+    }
 
-        tokio::spawn(async move {
-            if let Ok(stream) = acceptor.accept(stream).await {
-                let fut = Http::new()
-                    .serve_connection(stream, service_fn(serve));
+    let protocols = match &config.alpn {
+        Some(alpn_config) => Protocols::from_config(alpn_config)?,
+        None => Protocols::new(),
+    };
 
-                match fut.await {
-                    Ok(()) => (),
-                    Err(_) => (),
+    if cert.is_none() && !protocols.is_empty() {
+        return Err("alpn needs TLS, it can't be used with h2c.".into());
+    }
+
+    let acceptor = match (&config.early_data, &cert) {
+        (Some(early_data), Some(cert)) => Some(Acceptor::EarlyData(
+            early_tls::Acceptor::new(cert.clone(), &protocols.ids(), early_data)?
+        )),
+        (Some(_), None) => return Err("early data needs TLS, it can't be used with h2c.".into()),
+        (None, Some(cert)) => Some(Acceptor::Tls(
+            TlsAcceptor::from(Arc::new(util::rustls_server_config(cert.clone(), &protocols.ids())))
+        )),
+        (None, None) => None,
+    };
+
+    let listeners = accept_shards::bind(listener::resolve(&config.addr).await?, config.accept_shards)?;
+
+    let http3 = match (&config.http3, &cert) {
+        (Some(http3_config), Some(cert)) => Some(Http3::bind(
+            http3_config,
+            &config.addr,
+            cert.clone(),
+            config.early_data.is_some(),
+        ).await?),
+        (Some(_), None) => return Err("http3 needs TLS, it can't be used with h2c.".into()),
+        (None, _) => None,
+    };
+
+    let router = Arc::new(app_router(proxy, health.clone()));
+    let service = middleware::stack(
+        service_fn(move |req| router.serve(req)),
+        &config.middleware,
+    )?;
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (done_tx, mut done_rx) = mpsc::channel(1);
+
+    let state = State {
+        acceptor,
+        protocols,
+        access_log,
+        service,
+        alt_svc: http3.as_ref().map(Http3::alt_svc),
+        shutdown: shutdown_rx,
+        _done: done_tx,
+    };
+
+    if let Some(http3) = http3 {
+        http3.spawn(state.clone());
+    }
+
+    let (stop_tx, stop_rx) = watch::channel(());
+    let accepting = accept_all(listeners, state, stop_rx);
+
+    tokio::pin!(accepting);
+
+    tokio::select! {
+        res = &mut accepting => return res,
+        _ = shutdown_signal()? => (),
+    }
+
+    // Keep serving for a while so load balancers notice "/readyz" failing.
+    health.set_draining();
+    println!("draining for {} seconds", config.health.drain_secs);
+
+    tokio::select! {
+        res = &mut accepting => return res,
+        _ = tokio::time::sleep(Duration::from_secs(config.health.drain_secs)) => (),
+    }
+
+    // Listeners are closed once accept loops stop.
+    let _ = stop_tx.send(());
+    accepting.await?;
+
+    let _ = shutdown_tx.send(true);
+    let _ = done_rx.recv().await;
+
+    Ok(())
+}
+
+// Certificate isn't used when serving cleartext h2c.
+fn load_cert(config: &Config) -> Result<Option<Arc<ReloadableCert>>, AnyError> {
+    if config.h2c {
+        return Ok(None);
+    }
+
+    let (key_location, cert_location) = match (&config.key_location, &config.cert_location) {
+        (Some(key), Some(cert)) => (key, cert),
+        _ => return Err("key_location and cert_location are required unless h2c is enabled.".into()),
+    };
+
+    let cert = ReloadableCert::load(key_location, cert_location, &config.hostnames)?;
+
+    Ok(Some(Arc::new(cert)))
+}
+
+fn app_router(proxy: Option<Arc<Proxy>>, health: Health) -> Router {
+    let readyz_health = health.clone();
+
+    let router = Router::new()
+        .get("/healthz", move |_| {
+            let resp = health.healthz();
+
+            async { resp }
+        })
+        .get("/readyz", move |_| {
+            let resp = readyz_health.readyz();
+
+            async { resp }
+        });
+
+    match proxy {
+        Some(proxy) => router.fallback(move |req| {
+            let proxy = proxy.clone();
+
+            async move {
+                // Upstreams may not know about "early-data" header, so replaying is refused here.
+                if early_data::is_early(&req) && !req.method().is_idempotent() {
+                    return early_data::too_early();
                 }
+
+                let conn = req.extensions().get::<Arc<ConnInfo>>().unwrap().clone();
+
+                proxy.forward(req, conn.peer(), conn.scheme()).await
             }
+        }),
+        None => router.any("/*path", serve),
+    }
+}
+
+/// Run an accept loop for every listener until `stop` changes.
+///
+/// A single listener is accepted on main runtime. Shards are pinned to threads of their own,
+/// each with a single-threaded runtime that also serves connections the shard accepts.
+async fn accept_all(
+    listeners: Vec<std::net::TcpListener>,
+    state: State,
+    mut stop: watch::Receiver<()>,
+) -> Result<(), AnyError> {
+    if listeners.len() == 1 {
+        let listener = TcpListener::from_std(listeners.into_iter().next().unwrap())?;
+
+        return accept_until(&listener, state, stop.changed()).await;
+    }
+
+    let mut shards = Vec::new();
+
+    for listener in listeners {
+        let mut state = state.clone();
+        let mut stop = stop.clone();
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+
+        accept_shards::spawn_pinned(listener, move |listener| async move {
+            // Connections are dropped with shard's runtime, so thread waits for them before
+            // letting main task know it's done.
+            let (shard_done, mut connections) = mpsc::channel(1);
+            let done = std::mem::replace(&mut state._done, shard_done);
+
+            let _ = stopped_tx.send(accept_until(&listener, state, stop.changed()).await);
+            let _ = connections.recv().await;
+
+            drop(done);
+        })?;
+
+        shards.push(async move {
+            stopped_rx.await.unwrap_or_else(|_| Err("Accept shard stopped unexpectedly.".into()))
         });
     }
+
+    futures::future::try_join_all(shards).await?;
+
+    Ok(())
+}
+
+async fn accept_until(
+    listener: &TcpListener,
+    state: State,
+    until: impl Future,
+) -> Result<(), AnyError> {
+    tokio::pin!(until);
+
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                let (stream, peer) = res?;
+                let span = tracing::info_span!("accept", peer = %peer);
+
+                tokio::spawn(handle_connection(stream, peer, state.clone()).instrument(span));
+            },
+            _ = &mut until => return Ok(()),
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, peer: SocketAddr, state: State) {
+    match state.acceptor.clone() {
+        Some(Acceptor::Tls(acceptor)) => {
+            let span = tracing::info_span!("tls_handshake", sni = Empty, alpn = Empty, error = Empty);
+
+            let stream = match acceptor.accept(stream).instrument(span.clone()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    span.record("error", display(e));
+                    return;
+                },
+            };
+
+            let conn = Arc::new(ConnInfo::new(peer, stream.get_ref().1));
+            let alpn = stream.get_ref().1.get_alpn_protocol().map(<[u8]>::to_vec);
+
+            conn.record(&span);
+
+            serve_tls(stream, alpn, conn, None, state).await;
+        },
+        Some(Acceptor::EarlyData(acceptor)) => {
+            let span = tracing::info_span!("tls_handshake", sni = Empty, alpn = Empty, error = Empty);
+
+            let stream = match acceptor.accept(stream).instrument(span.clone()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    span.record("error", display(e));
+                    return;
+                },
+            };
+
+            let conn = Arc::new(stream.conn_info(peer));
+            let alpn = stream.alpn_protocol().map(<[u8]>::to_vec);
+            let early_reads = stream.early_reads();
+
+            conn.record(&span);
+
+            serve_tls(stream, alpn, conn, Some(early_reads), state).await;
+        },
+        None => {
+            // Http/2 with prior knowledge is detected from connection preface.
+            let conn = Arc::new(ConnInfo::from_parts(peer, false, None, None));
+
+            serve_connection(stream, conn, None, state, Http::new()).await;
+        },
+    }
+}
+
+/// Serve TLS `stream` with handler of negotiated `alpn`, or http when there is none.
+async fn serve_tls<IO>(
+    stream: IO,
+    alpn: Option<Vec<u8>>,
+    conn: Arc<ConnInfo>,
+    early_reads: Option<Arc<EarlyReads>>,
+    state: State,
+) where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let handler = alpn
+        .and_then(|alpn| state.protocols.get(&alpn))
+        .cloned();
+
+    match handler {
+        Some(handler) => {
+            let mut shutdown = state.shutdown.clone();
+
+            // Other protocols can't be closed gracefully, they are cut at shutdown.
+            tokio::select! {
+                _ = handler(Box::new(stream), conn) => (),
+                _ = shutdown.changed() => (),
+            }
+        },
+        None => serve_connection(stream, conn, early_reads, state, Http::new()).await,
+    }
+}
+
+/// Serve requests on `io` until it is closed or server shuts down.
+///
+/// Requests that may have arrived in early data, as told by `early_reads`, are marked with
+/// [`early_data::mark`].
+async fn serve_connection<IO>(
+    io: IO,
+    conn: Arc<ConnInfo>,
+    early_reads: Option<Arc<EarlyReads>>,
+    state: State,
+    http: Http,
+)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut shutdown = state.shutdown.clone();
+
+    let service = service_fn(move |mut req| {
+        if state.acceptor.is_none() && h2c::can_upgrade(&req) {
+            let upgrade = h2c::upgrade(req, state.clone(), conn.clone());
+
+            return Either::Left(upgrade.map(Ok));
+        }
+
+        if early_reads.as_ref().is_some_and(|e| e.take_request()) {
+            early_data::mark(&mut req);
+        }
+
+        let fut = state.dispatch(req, &conn);
+        let alt_svc = state.alt_svc.clone();
+
+        Either::Right(async move {
+            let mut resp = fut.await?;
+
+            if let Some(alt_svc) = alt_svc {
+                resp.headers_mut().insert(ALT_SVC, alt_svc);
+            }
+
+            Ok::<_, Infallible>(resp)
+        })
+    });
+
+    let fut = http
+        .serve_connection(io, service)
+        .with_upgrades();
+
+    tokio::pin!(fut);
+
+    tokio::select! {
+        _ = fut.as_mut() => (),
+        _ = shutdown.changed() => {
+            fut.as_mut().graceful_shutdown();
+
+            let _ = fut.await;
+        },
+    }
+}
+
+fn shutdown_signal() -> Result<impl Future<Output = ()>, AnyError> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => (),
+            _ = interrupt.recv() => (),
+        }
+    })
 }
 
 async fn serve(
   _req: Request<Body>
-) -> Result<Response<Body>, Infallible> {
-    let resp = Response::builder()
-        .body(Body::from("Hello, world!")).unwrap();
+) -> Response<Body> {
+    Response::builder()
+        .body(Body::from("Hello, world!")).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use health::HealthConfig;
 
-    Ok(resp)
+    use hyper::Method;
+
+    async fn call(router: &Router, method: Method, uri: &str) -> (u16, String) {
+        let req = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+        let resp = router.serve(req).await.unwrap();
+
+        let status = resp.status().as_u16();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn every_method_served() {
+        let router = app_router(None, Health::new(None, &HealthConfig::default()));
+
+        for method in [Method::GET, Method::POST, Method::PUT, Method::DELETE, Method::PATCH] {
+            for uri in ["/", "/a/b"] {
+                assert_eq!(call(&router, method.clone(), uri).await, (200, "Hello, world!".to_owned()));
+            }
+        }
+    }
 }
@@ -0,0 +1,102 @@
+use super::upstream::Upstream;
+
+use crate::AnyError;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Client, Request};
+use hyper::client::HttpConnector;
+
+use serde::Deserialize;
+
+use tokio::time::{interval, timeout};
+
+#[derive(Deserialize, Clone)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_path")]
+    path: String,
+    #[serde(default = "default_interval")]
+    interval_secs: u64,
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            path: default_path(),
+            interval_secs: default_interval(),
+            timeout_secs: default_timeout(),
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    pub fn validate(&self) -> Result<(), AnyError> {
+        if self.interval_secs == 0 {
+            return Err("health_check.interval_secs must be at least 1.".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Spawn a task that periodically checks upstream and updates its state.
+pub fn spawn_health_check(
+    client: Client<HttpConnector>,
+    upstream: Arc<Upstream>,
+    config: HealthCheckConfig,
+) {
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(config.interval_secs));
+        let limit = Duration::from_secs(config.timeout_secs);
+
+        loop {
+            ticker.tick().await;
+
+            let healthy = check(&client, &upstream, &config.path, limit).await;
+            let was_healthy = upstream.set_healthy(healthy);
+
+            if healthy != was_healthy {
+                let state = if healthy { "healthy" } else { "unhealthy" };
+
+                println!("upstream {} is {}", upstream.authority(), state);
+            }
+        }
+    });
+}
+
+async fn check(
+    client: &Client<HttpConnector>,
+    upstream: &Upstream,
+    path: &str,
+    limit: Duration,
+) -> bool {
+    let req = Request::builder()
+        .uri(format!("http://{}{}", upstream.authority(), path))
+        .header("user-agent", "rustls-server health check")
+        .body(Body::empty());
+
+    let req = match req {
+        Ok(req) => req,
+        Err(_) => return false,
+    };
+
+    match timeout(limit, client.request(req)).await {
+        Ok(Ok(resp)) => resp.status().is_success(),
+        _ => false,
+    }
+}
+
+fn default_path() -> String {
+    "/".to_owned()
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+fn default_timeout() -> u64 {
+    2
+}
@@ -0,0 +1,473 @@
+mod health;
+mod upstream;
+
+use health::{spawn_health_check, HealthCheckConfig};
+use upstream::{ActiveGuard, Balance, Balancer, Upstream};
+
+use crate::AnyError;
+use crate::early_data;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::{Body, Client, Request, Response, StatusCode, Version};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, CONNECTION, HOST, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+
+use hop_headers::remove_hop_headers;
+
+use serde::Deserialize;
+
+use tracing::{Instrument, Span};
+use tracing::field::Empty;
+
+#[derive(Deserialize)]
+pub struct ProxyConfig {
+    // Plaintext http servers as "host:port".
+    upstreams: Vec<String>,
+    #[serde(default)]
+    balance: Balance,
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+}
+
+impl ProxyConfig {
+    pub fn validate(&self) -> Result<(), AnyError> {
+        if self.upstreams.is_empty() {
+            return Err("No upstream server in proxy config.".into());
+        }
+
+        self.health_check.validate()
+    }
+}
+
+pub struct Proxy {
+    client: Client<HttpConnector>,
+    balancer: Balancer,
+    health_check: HealthCheckConfig,
+}
+
+impl Proxy {
+    pub fn new(config: &ProxyConfig) -> Result<Self, AnyError> {
+        config.validate()?;
+
+        let upstreams = config.upstreams.iter()
+            .map(|authority| Arc::new(Upstream::new(authority.clone())))
+            .collect();
+
+        Ok(Self {
+            client: Client::new(),
+            balancer: Balancer::new(upstreams, config.balance),
+            health_check: config.health_check.clone(),
+        })
+    }
+
+    pub fn start_health_checks(&self) {
+        for upstream in self.balancer.upstreams() {
+            spawn_health_check(
+                self.client.clone(),
+                upstream.clone(),
+                self.health_check.clone(),
+            );
+        }
+    }
+
+    /// Number of upstreams that passed their last health check, and of all upstreams.
+    /// Upstreams that weren't checked yet don't count as healthy.
+    pub fn upstream_health(&self) -> (usize, usize) {
+        let upstreams = self.balancer.upstreams();
+        let healthy = upstreams.iter().filter(|u| u.is_checked_healthy()).count();
+
+        (healthy, upstreams.len())
+    }
+
+    /// Forward request to an upstream server and return its response.
+    pub async fn forward(
+        &self,
+        req: Request<Body>,
+        peer: SocketAddr,
+        proto: &str,
+    ) -> Response<Body> {
+        let request_id = middleware::request_id(&req).unwrap_or("-").to_owned();
+
+        match self.try_forward(req, peer, proto).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                println!("error proxying request {}: {}", request_id, e);
+
+                bad_gateway()
+            },
+        }
+    }
+
+    async fn try_forward(
+        &self,
+        mut req: Request<Body>,
+        peer: SocketAddr,
+        proto: &str,
+    ) -> Result<Response<Body>, AnyError> {
+        let guard = self.balancer.pick().ok_or("no healthy upstream")?;
+
+        let client_upgrade = match is_upgrade(&req) {
+            true => Some(hyper::upgrade::on(&mut req)),
+            false => None,
+        };
+
+        let request_id = middleware::request_id(&req).unwrap_or("-").to_owned();
+
+        prepare_request(&mut req, guard.upstream().authority(), peer, proto)?;
+
+        let mut resp = self.client.request(req).await?;
+
+        if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+            if let Some(client_upgrade) = client_upgrade {
+                let upstream_upgrade = hyper::upgrade::on(&mut resp);
+
+                let span = tracing::info_span!("upgrade", protocol = Empty);
+
+                if let Some(protocol) = resp.headers().get(UPGRADE).and_then(|v| v.to_str().ok()) {
+                    span.record("protocol", protocol);
+                }
+
+                tokio::spawn(async move {
+                    if let Err(e) = tunnel(client_upgrade, upstream_upgrade, guard).await {
+                        println!("error proxying upgraded connection {}: {}", request_id, e);
+                    }
+                }.instrument(span));
+
+                return Ok(resp);
+            }
+        }
+
+        remove_hop_headers(resp.headers_mut());
+
+        // Body is passed on as it is, with trailers. Upstream stops
+        // counting as active once it has answered.
+        drop(guard);
+
+        Ok(resp)
+    }
+}
+
+async fn tunnel(
+    client: OnUpgrade,
+    upstream: OnUpgrade,
+    _guard: ActiveGuard,
+) -> Result<(), AnyError> {
+    let (mut client, mut upstream) = tokio::try_join!(client, upstream)?;
+
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+
+    Ok(())
+}
+
+fn prepare_request(
+    req: &mut Request<Body>,
+    authority: &str,
+    peer: SocketAddr,
+    proto: &str,
+) -> Result<(), AnyError> {
+    // Http2 requests carry host in uri instead of a header.
+    let host = match req.headers().get(HOST) {
+        Some(host) => Some(host.clone()),
+        None => match req.uri().authority() {
+            Some(a) => Some(HeaderValue::from_str(a.as_str())?),
+            None => None,
+        },
+    };
+
+    let path = req.uri().path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    *req.uri_mut() = format!("http://{}{}", authority, path).parse()?;
+    *req.version_mut() = Version::HTTP_11;
+
+    let early = early_data::is_early(req);
+
+    let upgrade = match is_upgrade(req) {
+        true => req.headers().get(UPGRADE).cloned(),
+        false => None,
+    };
+
+    let headers = req.headers_mut();
+
+    remove_hop_headers(headers);
+
+    if let Some(upgrade) = upgrade {
+        headers.insert(UPGRADE, upgrade);
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+
+    if let Some(host) = host {
+        headers.insert(HOST, host.clone());
+        headers.insert("x-forwarded-host", host);
+    }
+
+    let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+        Some(prev) => format!("{}, {}", prev, peer.ip()),
+        None => peer.ip().to_string(),
+    };
+
+    headers.insert("x-forwarded-for", HeaderValue::from_str(&forwarded_for)?);
+    headers.insert("x-forwarded-proto", HeaderValue::from_str(proto)?);
+
+    // SEE: https://datatracker.ietf.org/doc/html/rfc8470#section-5.1
+    if early {
+        headers.insert("early-data", HeaderValue::from_static("1"));
+    }
+
+    // Upstream continues trace of this request.
+    telemetry::inject(&Span::current(), headers);
+
+    Ok(())
+}
+
+fn is_upgrade(req: &Request<Body>) -> bool {
+    req.version() == Version::HTTP_11 && req.headers().contains_key(UPGRADE)
+}
+
+fn bad_gateway() -> Response<Body> {
+    Response::builder()
+        .status(502)
+        .header("content-type", "text/plain")
+        .body(Body::from("Bad Gateway"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use hyper::Server;
+    use hyper::service::{make_service_fn, service_fn};
+
+    use serde_json::json;
+
+    const PEER: ([u8; 4], u16) = ([192, 0, 2, 7], 50000);
+
+    // Plaintext upstream answering with its name, and request headers as body.
+    fn spawn_upstream(name: &'static str, healthy: Arc<AtomicBool>) -> String {
+        let make_service = make_service_fn(move |_| {
+            let healthy = healthy.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| respond(name, healthy.clone(), req)))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+
+        tokio::spawn(server);
+
+        addr.to_string()
+    }
+
+    async fn respond(name: &str, healthy: Arc<AtomicBool>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let status = match req.uri().path() {
+            "/health" if !healthy.load(Ordering::Relaxed) => StatusCode::SERVICE_UNAVAILABLE,
+            "/slow" => {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                StatusCode::OK
+            },
+            _ => StatusCode::OK,
+        };
+
+        let headers: String = req.headers().iter()
+            .map(|(name, value)| format!("{}: {}\n", name, value.to_str().unwrap()))
+            .collect();
+
+        let resp = Response::builder()
+            .status(status)
+            .header("upstream", name)
+            .header("keep-alive", "timeout=5")
+            .header("connection", "x-hop")
+            .header("x-hop", "1")
+            .body(Body::from(headers))
+            .unwrap();
+
+        Ok(resp)
+    }
+
+    fn proxy(upstreams: &[&str], balance: &str) -> Proxy {
+        let config = json!({
+            "upstreams": upstreams,
+            "balance": balance,
+            "health_check": { "path": "/health", "interval_secs": 1, "timeout_secs": 1 },
+        });
+
+        Proxy::new(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .header(HOST, "example.com")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn forward(proxy: &Proxy, req: Request<Body>) -> (Response<Body>, String) {
+        let resp = proxy.forward(req, PEER.into(), "https").await;
+
+        let upstream = match resp.headers().get("upstream") {
+            Some(name) => name.to_str().unwrap().to_owned(),
+            None => String::new(),
+        };
+
+        (resp, upstream)
+    }
+
+    async fn body_text(resp: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn healthy() -> Arc<AtomicBool> {
+        Arc::new(AtomicBool::new(true))
+    }
+
+    #[tokio::test]
+    async fn round_robin() {
+        let a = spawn_upstream("a", healthy());
+        let b = spawn_upstream("b", healthy());
+        let proxy = proxy(&[&a, &b], "round_robin");
+
+        let mut picked = Vec::new();
+
+        for _ in 0..4 {
+            picked.push(forward(&proxy, request("/")).await.1);
+        }
+
+        assert_eq!(picked, ["a", "b", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn least_connections() {
+        let a = spawn_upstream("a", healthy());
+        let b = spawn_upstream("b", healthy());
+        let proxy = Arc::new(proxy(&[&a, &b], "least_connections"));
+
+        let slow = tokio::spawn({
+            let proxy = proxy.clone();
+
+            async move { forward(&proxy, request("/slow")).await.1 }
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // "a" is busy with slow request.
+        assert_eq!(forward(&proxy, request("/")).await.1, "b");
+        assert_eq!(slow.await.unwrap(), "a");
+        assert_eq!(forward(&proxy, request("/")).await.1, "a");
+    }
+
+    #[tokio::test]
+    async fn forwarded_headers() {
+        let a = spawn_upstream("a", healthy());
+        let proxy = proxy(&[&a], "round_robin");
+
+        let mut req = request("/");
+
+        req.headers_mut().insert("x-forwarded-for", HeaderValue::from_static("198.51.100.1"));
+
+        let (resp, _) = forward(&proxy, req).await;
+        let body = body_text(resp).await;
+
+        assert!(body.contains("x-forwarded-for: 198.51.100.1, 192.0.2.7\n"), "{}", body);
+        assert!(body.contains("x-forwarded-proto: https\n"), "{}", body);
+        assert!(body.contains("x-forwarded-host: example.com\n"), "{}", body);
+        assert!(body.contains("host: example.com\n"), "{}", body);
+
+        let (resp, _) = forward(&proxy, request("/")).await;
+
+        assert!(body_text(resp).await.contains("x-forwarded-for: 192.0.2.7\n"));
+    }
+
+    #[tokio::test]
+    async fn hop_headers_removed() {
+        let a = spawn_upstream("a", healthy());
+        let proxy = proxy(&[&a], "round_robin");
+
+        let mut req = request("/");
+        let headers = req.headers_mut();
+
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, x-listed"));
+        headers.insert("x-listed", HeaderValue::from_static("1"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("te", HeaderValue::from_static("trailers"));
+        headers.insert("proxy-authorization", HeaderValue::from_static("Basic eDp5"));
+        headers.insert("x-kept", HeaderValue::from_static("1"));
+
+        let (resp, _) = forward(&proxy, req).await;
+
+        assert!(!resp.headers().contains_key(CONNECTION));
+        assert!(!resp.headers().contains_key("keep-alive"));
+        assert!(!resp.headers().contains_key("x-hop"));
+
+        let body = body_text(resp).await;
+
+        assert!(body.contains("x-kept: 1\n"), "{}", body);
+
+        for name in ["x-listed", "te", "proxy-authorization", "keep-alive"] {
+            assert!(!body.contains(&format!("{}: ", name)), "{} in {}", name, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn health_check_marks_down_and_up() {
+        let a_healthy = healthy();
+        let a = spawn_upstream("a", a_healthy.clone());
+        let b = spawn_upstream("b", healthy());
+        let proxy = proxy(&[&a, &b], "round_robin");
+
+        proxy.start_health_checks();
+
+        a_healthy.store(false, Ordering::Relaxed);
+        wait_until(|| !proxy.balancer.upstreams()[0].is_healthy()).await;
+
+        for _ in 0..3 {
+            assert_eq!(forward(&proxy, request("/")).await.1, "b");
+        }
+
+        a_healthy.store(true, Ordering::Relaxed);
+        wait_until(|| proxy.balancer.upstreams()[0].is_healthy()).await;
+
+        let picked = [forward(&proxy, request("/")).await.1, forward(&proxy, request("/")).await.1];
+
+        assert!(picked.contains(&"a".to_owned()), "{:?}", picked);
+    }
+
+    #[tokio::test]
+    async fn no_healthy_upstream() {
+        let a = spawn_upstream("a", Arc::new(AtomicBool::new(false)));
+        let proxy = proxy(&[&a], "round_robin");
+
+        proxy.start_health_checks();
+
+        wait_until(|| !proxy.balancer.upstreams()[0].is_healthy()).await;
+
+        let (resp, _) = forward(&proxy, request("/")).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    async fn wait_until(f: impl Fn() -> bool) {
+        for _ in 0..50 {
+            if f() {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        panic!("condition not met in 5 seconds");
+    }
+}
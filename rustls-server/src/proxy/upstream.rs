@@ -0,0 +1,125 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+pub struct Upstream {
+    authority: String,
+    healthy: AtomicBool,
+    // Set once first health check finished.
+    checked: AtomicBool,
+    active: AtomicUsize,
+}
+
+impl Upstream {
+    pub fn new(authority: String) -> Self {
+        Self {
+            authority,
+            // Assume healthy until first check says otherwise.
+            healthy: AtomicBool::new(true),
+            checked: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Healthy and checked at least once.
+    pub fn is_checked_healthy(&self) -> bool {
+        self.checked.load(Ordering::Relaxed) && self.is_healthy()
+    }
+
+    /// Returns previous state.
+    pub fn set_healthy(&self, healthy: bool) -> bool {
+        let was_healthy = self.healthy.swap(healthy, Ordering::Relaxed);
+        self.checked.store(true, Ordering::Relaxed);
+
+        was_healthy
+    }
+
+    fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts as an active connection of upstream until dropped.
+pub struct ActiveGuard {
+    upstream: Arc<Upstream>,
+}
+
+impl ActiveGuard {
+    pub fn new(upstream: Arc<Upstream>) -> Self {
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+
+        Self { upstream }
+    }
+
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Balancer {
+    upstreams: Vec<Arc<Upstream>>,
+    balance: Balance,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(upstreams: Vec<Arc<Upstream>>, balance: Balance) -> Self {
+        Self {
+            upstreams,
+            balance,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    /// Pick a healthy upstream. Returns `None` if there isn't any.
+    pub fn pick(&self) -> Option<ActiveGuard> {
+        let upstream = match self.balance {
+            Balance::RoundRobin => self.round_robin(),
+            Balance::LeastConnections => self.least_connections(),
+        };
+
+        upstream.map(|u| ActiveGuard::new(u.clone()))
+    }
+
+    fn round_robin(&self) -> Option<&Arc<Upstream>> {
+        let len = self.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+        (0..len)
+            .map(|i| &self.upstreams[(start + i) % len])
+            .find(|u| u.is_healthy())
+    }
+
+    fn least_connections(&self) -> Option<&Arc<Upstream>> {
+        self.upstreams.iter()
+            .filter(|u| u.is_healthy())
+            .min_by_key(|u| u.active())
+    }
+}
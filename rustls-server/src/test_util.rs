@@ -0,0 +1,102 @@
+//! Helpers shared by tests of several modules.
+
+use crate::State;
+use crate::cert::ReloadableCert;
+
+use std::sync::Arc;
+
+use middleware::BoxService;
+
+use tokio::sync::{mpsc, watch};
+
+use rustls_quic::{ClientConfig, DigitallySignedStruct, Error, SignatureScheme};
+use rustls_quic::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls_quic::crypto::{ring, verify_tls12_signature, verify_tls13_signature};
+use rustls_quic::pki_types::{CertificateDer, ServerName, UnixTime};
+
+/// Certificate in "certs" directory.
+pub fn cert() -> Arc<ReloadableCert> {
+    let dir = env!("CARGO_MANIFEST_DIR");
+    let key = format!("{}/certs/key.pem", dir);
+    let cert = format!("{}/certs/cert.pem", dir);
+
+    Arc::new(ReloadableCert::load(&key, &cert, &[]).unwrap())
+}
+
+/// Client config that only trusts `cert`. ALPN protocols aren't set.
+pub fn pinned_client(cert: &ReloadableCert) -> ClientConfig {
+    let pinned = Pinned(CertificateDer::from(cert.key_pair().certs[0].0.clone()));
+
+    ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(pinned))
+        .with_no_client_auth()
+}
+
+/// Cleartext state that serves requests with `service`.
+///
+/// Server shuts down when returned sender is set or dropped.
+pub fn state(service: BoxService) -> (State, watch::Sender<bool>) {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (done_tx, _) = mpsc::channel(1);
+
+    let state = State {
+        acceptor: None,
+        protocols: crate::alpn::Protocols::new(),
+        access_log: None,
+        service,
+        alt_svc: None,
+        shutdown: shutdown_rx,
+        _done: done_tx,
+    };
+
+    (state, shutdown_tx)
+}
+
+// Test certificate is self-signed with CA flag set, which webpki refuses as a leaf.
+#[derive(Debug)]
+struct Pinned(CertificateDer<'static>);
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        match end_entity == &self.0 {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(Error::General("unexpected certificate".to_owned())),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        let algorithms = ring::default_provider().signature_verification_algorithms;
+
+        verify_tls12_signature(message, cert, dss, &algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        let algorithms = ring::default_provider().signature_verification_algorithms;
+
+        verify_tls13_signature(message, cert, dss, &algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        ring::default_provider().signature_verification_algorithms.supported_schemes()
+    }
+}
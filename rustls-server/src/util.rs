@@ -1,58 +1,86 @@
-use std::io::BufReader;
-use std::fs::File;
+use std::io::BufRead;
+use std::sync::Arc;
 
 use rustls::internal::pemfile::{certs, pkcs8_private_keys};
-use rustls::{PrivateKey, Certificate, NoClientAuth, ServerConfig};
+use rustls::{PrivateKey, Certificate, NoClientAuth, ResolvesServerCert, ServerConfig};
+use rustls::sign::{any_supported_type, CertifiedKey};
+
+use pem_source::Source;
 
 type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
+/// Server config that negotiates `protocols` before http ones.
 pub fn rustls_server_config(
-  key: &str,
-  cert: &str
-) -> Result<ServerConfig, AnyError> {
+  resolver: Arc<dyn ResolvesServerCert>,
+  protocols: &[Vec<u8>],
+) -> ServerConfig {
     let mut config = ServerConfig::new(NoClientAuth::new());
 
-    let mut key_reader = get_file_reader(key)?;
-    let mut cert_reader = get_file_reader(cert)?;
+    config.cert_resolver = resolver;
+
+    config.set_protocols(&alpn_protocols(protocols));
+
+    config
+}
+
+/// ALPN identifiers to advertise, `protocols` before http ones.
+pub fn alpn_protocols(protocols: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut protocols = protocols.to_vec();
+    protocols.extend([b"h2".to_vec(), b"http/1.1".to_vec()]);
+
+    protocols
+}
+
+/// Certificate chain and private key as read from pem files.
+pub struct KeyPair {
+    pub certs: Vec<Certificate>,
+    pub key: PrivateKey,
+}
 
-    let key = get_private_key(&mut key_reader)?;
-    let certs = get_cert_chain(&mut cert_reader)?;
+/// Load key and certificate chain. See [`Source`] for accepted locations.
+pub fn load_key_pair(
+  key: &str,
+  cert: &str
+) -> Result<KeyPair, AnyError> {
+    let key_source = Source::parse(key);
+    let cert_source = Source::parse(cert);
 
-    config.set_single_cert(certs, key)
-        .map_err(|_| "Invalid certificate chain or private key.")?;
+    let key = get_private_key(&key_source, &mut key_source.read()?.as_slice())?;
+    let certs = get_cert_chain(&cert_source, &mut cert_source.read()?.as_slice())?;
 
-    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(KeyPair { certs, key })
+}
 
-    Ok(config)
+pub fn certified_key(pair: &KeyPair) -> Result<CertifiedKey, AnyError> {
+    let signing_key = any_supported_type(&pair.key)
+        .map_err(|_| "Private key is not a valid RSA, ECDSA or Ed25519 key.")?;
+
+    Ok(CertifiedKey::new(pair.certs.clone(), Arc::new(signing_key)))
 }
 
 fn get_cert_chain(
-  reader: &mut BufReader<File>
+  source: &Source<'_>,
+  reader: &mut dyn BufRead
 ) -> Result<Vec<Certificate>, AnyError> {
     match certs(reader) {
-        Ok(certs) => Ok(certs),
-        Err(_) => Err("Invalid certificate chain file.".into()),
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        Ok(_) => Err(format!("No certificate found in {}.", source).into()),
+        Err(_) => Err(format!("Invalid certificate chain in {}.", source).into()),
     }
 }
 
 fn get_private_key(
-  reader: &mut BufReader<File>
+  source: &Source<'_>,
+  reader: &mut dyn BufRead
 ) -> Result<PrivateKey, AnyError> {
     match pkcs8_private_keys(reader) {
         Ok(mut keys) => {
-            if keys.len() > 0 {
+            if !keys.is_empty() {
                 Ok(keys.remove(0))
             } else {
-                Err("No private key found in file.".into())
+                Err(format!("No private key found in {}.", source).into())
             }
         },
-        Err(_) => Err("Invalid private key file.".into()),
-    }
-}
-
-fn get_file_reader(file: &str) -> Result<BufReader<File>, AnyError> {
-    match File::open(file) {
-        Ok(file) => Ok(BufReader::new(file)),
-        Err(_) => Err(format!("Can't open {:?}.", file).into()),
+        Err(_) => Err(format!("Invalid private key in {}.", source).into()),
     }
 }
@@ -12,8 +12,6 @@ use futures::stream::StreamExt;
 use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::tungstenite::Message;
 
-use std::convert::Infallible;
-
 use hyper::{Body, Request, Response};
 use hyper::service::service_fn;
 use hyper::server::conn::Http;
@@ -21,85 +19,112 @@ use hyper::upgrade::Upgraded;
 
 use sha1::{Digest, Sha1};
 
-const INDEX: &'static [u8] = include_bytes!("../html/index.html");
+use middleware::MiddlewareConfig;
+
+use router::Router;
+
+use telemetry::Telemetry;
+
+use tracing::Instrument;
+use tracing::field::{display, Empty};
+
+const INDEX: &[u8] = include_bytes!("../html/index.html");
 
 pub type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
 #[tokio::main]
 async fn main() -> Result<(), AnyError> {
+    // Spans are exported over OTLP when this is set.
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").ok();
+    let _telemetry = Telemetry::init("rustls-websocket", endpoint.as_deref())?;
+
     let server_config = Arc::new(
         rustls_config::server_config("certs/key.pem", "certs/cert.pem")?
     );
 
+    let router = Arc::new(
+        Router::new()
+            .any("/websocket", |req| async {
+                upgrade(req).unwrap_or_else(|_| default_error())
+            })
+            // Every other path gets the page.
+            .fallback(|_| async { index() })
+    );
+
+    let service = middleware::stack(
+        service_fn(move |req| router.serve(req)),
+        &MiddlewareConfig::default(),
+    )?;
+
     let acceptor = TlsAcceptor::from(server_config);
     let listener = TcpListener::bind("127.0.0.1:3443").await?;
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = listener.accept().await?;
         let acceptor = acceptor.clone();
+        let service = service.clone();
+
+        let span = tracing::info_span!("accept", peer = %peer);
 
         tokio::spawn(async move {
-            if let Ok(stream) = acceptor.accept(stream).await {
-                let fut = Http::new()
-                    .serve_connection(stream, service_fn(serve))
-                    .with_upgrades();
-
-                match fut.await {
-                    Ok(()) => (),
-                    Err(_) => (),
-                }
+            let handshake = tracing::info_span!("tls_handshake", sni = Empty, error = Empty);
+
+            match acceptor.accept(stream).instrument(handshake.clone()).await {
+                Ok(stream) => {
+                    if let Some(sni) = stream.get_ref().1.get_sni_hostname() {
+                        handshake.record("sni", sni);
+                    }
+
+                    let fut = Http::new()
+                        .serve_connection(stream, service)
+                        .with_upgrades();
+
+                    let _ = fut.await;
+                },
+                Err(e) => {
+                    handshake.record("error", display(e));
+                },
             }
-
-        });
+        }.instrument(span));
     }
 }
 
-async fn serve(
-    req: Request<Body>,
-) -> Result<Response<Body>, Infallible> {
-    match try_serve(req).await {
-        Ok(resp) => Ok(resp),
-        Err(_) => Ok(default_error()),
-    }
+fn index() -> Response<Body> {
+    Response::builder()
+        .status(200)
+        .header("content-type", "text/html")
+        .body(Body::from(INDEX))
+        .unwrap()
 }
 
-async fn try_serve(
+fn upgrade(
     mut req: Request<Body>,
 ) -> Result<Response<Body>, AnyError> {
-    let path = req.uri().path();
-
-    if path == "/websocket" {
-        let key = req.headers().get("sec-websocket-key").ok_or("")?.to_str()?.to_owned();
-
-        tokio::task::spawn(async move {
-            match hyper::upgrade::on(&mut req).await {
-                Ok(upgraded) => {
-                    if let Err(e) = handle_connection(upgraded).await {
-                        println!("error handling websocket: {}", e)
-                    };
-                }
-                Err(e) => println!("upgrade error: {}", e),
+    let key = req.headers().get("sec-websocket-key").ok_or("")?.to_str()?.to_owned();
+
+    // Lasts as long as websocket, messages are its children.
+    let span = tracing::info_span!("websocket_upgrade");
+
+    tokio::task::spawn(async move {
+        match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => {
+                if let Err(e) = handle_connection(upgraded).await {
+                    println!("error handling websocket: {}", e)
+                };
             }
-        });
-
-        let resp = Response::builder()
-            .status(101)
-            .header("connection", "Upgrade")
-            .header("upgrade", "websocket")
-            .header("sec-websocket-accept", convert_key(key.as_bytes()))
-            .body(Body::empty())
-            .unwrap();
-
-        Ok(resp)
-    } else {
-        let resp = Response::builder()
-            .status(200)
-            .header("content-type", "text/html")
-            .body(Body::from(INDEX))
-            .unwrap();
-
-        Ok(resp)
-    }
+            Err(e) => println!("upgrade error: {}", e),
+        }
+    }.instrument(span));
+
+    let resp = Response::builder()
+        .status(101)
+        .header("connection", "Upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-accept", convert_key(key.as_bytes()))
+        .body(Body::empty())
+        .unwrap();
+
+    Ok(resp)
 }
 
 async fn handle_connection(stream: Upgraded) -> Result<(), AnyError> {
@@ -110,22 +135,35 @@ async fn handle_connection(stream: Upgraded) -> Result<(), AnyError> {
     ).await;
 
     while let Some(msg_res) = ws_stream.next().await {
-        if let Message::Text(s) = msg_res? {
+        let msg = msg_res?;
+        let span = tracing::info_span!("websocket_message", kind = message_kind(&msg), bytes = msg.len());
+
+        if let Message::Text(s) = msg {
             let msg = Message::Text(s);
 
-            ws_stream.send(msg).await?;
+            ws_stream.send(msg).instrument(span).await?;
         }
     }
 
     Ok(())
 }
 
+fn message_kind(msg: &Message) -> &'static str {
+    match msg {
+        Message::Text(_) => "text",
+        Message::Binary(_) => "binary",
+        Message::Ping(_) => "ping",
+        Message::Pong(_) => "pong",
+        Message::Close(_) => "close",
+    }
+}
+
 fn convert_key(input: &[u8]) -> String {
     const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
     let mut sha1 = Sha1::default();
     sha1.update(input);
     sha1.update(WS_GUID);
-    base64::encode(&sha1.finalize())
+    base64::encode(sha1.finalize())
 }
 
 fn default_error() -> Response<Body> {
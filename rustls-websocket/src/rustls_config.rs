@@ -35,7 +35,7 @@ pub fn server_config(
 fn get_first_private_key(loc: &str) -> Result<PrivateKey, AnyError> {
     let mut privkeys = pkcs8_private_keys(
         &mut BufReader::new(
-            File::open(&loc)?
+            File::open(loc)?
         )
     ).map_err(|_| "cant get private key")?;
 
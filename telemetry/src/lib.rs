@@ -0,0 +1,130 @@
+//! Tracing setup shared by hyper servers.
+//!
+//! Events are written to stderr. When an OTLP endpoint is given, spans are also
+//! exported to it and W3C `traceparent` headers connect them to other services.
+
+use hyper::HeaderMap;
+use hyper::header::{HeaderName, HeaderValue};
+
+use opentelemetry::global;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+type AnyError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Installed tracing subscriber. Call [`Telemetry::shutdown`] before exiting
+/// so buffered spans are sent.
+pub struct Telemetry {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Telemetry {
+    /// Install global subscriber. Spans are exported to `otlp_endpoint`, a full
+    /// OTLP/HTTP url like "http://127.0.0.1:4318/v1/traces", when it is given.
+    ///
+    /// Log level comes from `RUST_LOG`, "info" when it isn't set.
+    pub fn init(service_name: &str, otlp_endpoint: Option<&str>) -> Result<Self, AnyError> {
+        let filter = EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::new("info"));
+
+        let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+
+        let provider = match otlp_endpoint {
+            Some(endpoint) => Some(tracer_provider(service_name, endpoint)?),
+            None => None,
+        };
+
+        let otel = provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name.to_owned()))
+        });
+
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(fmt)
+            .with(otel)
+            .try_init()?;
+
+        Ok(Self { provider })
+    }
+
+    /// Flush pending spans and stop exporting.
+    pub fn shutdown(self) {
+        if let Some(provider) = self.provider {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Make `span` a child of the trace in `traceparent` header, if there is one.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    if !headers.contains_key("traceparent") {
+        return;
+    }
+
+    let cx = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+
+    let _ = span.set_parent(cx);
+}
+
+/// Write `traceparent` header that continues trace of `span`.
+pub fn inject(span: &Span, headers: &mut HeaderMap) {
+    let cx = span.context();
+
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderInjector(headers))
+    });
+}
+
+fn tracer_provider(service_name: &str, endpoint: &str) -> Result<SdkTracerProvider, AnyError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    let resource = Resource::builder()
+        .with_service_name(service_name.to_owned())
+        .build();
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(provider)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -36,10 +36,10 @@ where
     async fn run(mut self) {
         loop {
             tokio::select! {
-                opt = self.ws.next() => if let Err(_) = self.recv_msg(opt).await {
+                opt = self.ws.next() => if self.recv_msg(opt).await.is_err() {
                     break;
                 },
-                opt = self.rx.recv() => if let Err(_) = self.send_msg(opt).await {
+                opt = self.rx.recv() => if self.send_msg(opt).await.is_err() {
                     break;
                 }
             }
@@ -65,7 +65,7 @@ where
             _ => return Err(())
         };
 
-        if let Err(_) = self.tx.send(ws_msg).await {
+        if self.tx.send(ws_msg).await.is_err() {
             return Err(());
         }
 
@@ -81,7 +81,7 @@ impl SenderHandle {
     pub async fn send(&self, msg: Message) -> WsResult<()> {
         let (tx, rx) = oneshot::channel();
 
-        if let Err(_) = self.tx.send((msg, tx)).await {
+        if self.tx.send((msg, tx)).await.is_err() {
             return Err(WsError::AlreadyClosed);
         }
 
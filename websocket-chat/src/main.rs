@@ -5,7 +5,7 @@ use actor::{start_actor, SenderHandle, ReceiverHandle};
 use room::{ChatRoom, RoomReceiver};
 
 use std::include_bytes;
-use std::convert::Infallible;
+use std::sync::Arc;
 
 use tokio::net::TcpListener;
 
@@ -20,44 +20,57 @@ use tokio_tungstenite::tungstenite::protocol::Role;
 
 use sha1::{Digest, Sha1};
 
+use middleware::MiddlewareConfig;
+
+use router::Router;
+
 type AnyError = Box<dyn std::error::Error + Send + Sync>;
 
-pub static INDEX: &'static [u8] = include_bytes!("../html/index.html");
+pub static INDEX: &[u8] = include_bytes!("../html/index.html");
 
 #[tokio::main]
 async fn main() -> Result<(), AnyError> {
     let room = ChatRoom::new();
-    let room: &'static ChatRoom = Box::leak(Box::new(room));
+
+    let router = Arc::new(
+        Router::new()
+            .any("/", |_| async { index() })
+            .any("/websocket", move |req| {
+                let room = room.clone();
+
+                async move {
+                    upgrade(req, room).await.unwrap_or_else(|_| not_found())
+                }
+            })
+    );
+
+    let service = middleware::stack(
+        service_fn(move |req| router.serve(req)),
+        &MiddlewareConfig::default(),
+    )?;
 
     let listener = TcpListener::bind("127.0.0.1:3000").await?;
 
     loop {
         let (stream, _addr) = listener.accept().await?;
+        let service = service.clone();
 
         tokio::spawn(async move {
             let fut = Http::new()
-                .serve_connection(stream, service_fn(|req| serve(req, room)))
+                .serve_connection(stream, service)
                 .with_upgrades();
 
-            match fut.await {
-                Ok(()) => (),
-                Err(_) => (),
-            }
+            let _ = fut.await;
         });
     }
 }
 
-async fn serve(
-    req: Request<Body>,
-    room: &'static ChatRoom
-) -> Result<Response<Body>, Infallible> {
-    match try_serve(req, room).await {
-        Ok(resp) => Ok(resp),
-        Err(_) => Ok(not_found()),
-    }
+fn index() -> Response<Body> {
+    Response::builder()
+        .body(Body::from(INDEX))
+        .unwrap()
 }
 
-
 fn not_found() -> Response<Body> {
     Response::builder()
         .status(404)
@@ -65,27 +78,6 @@ fn not_found() -> Response<Body> {
         .unwrap()
 }
 
-async fn try_serve(
-    req: Request<Body>,
-    room: &'static ChatRoom
-) -> Result<Response<Body>, AnyError> {
-    match req.uri().path() {
-        "/" => {
-            let resp = Response::builder()
-                .body(Body::from(INDEX))
-                .unwrap();
-
-            Ok(resp)
-        },
-        "/websocket" => {
-            Ok(
-                upgrade(req, room.clone()).await?
-            )
-        },
-        _ => Ok(not_found())
-    }
-}
-
 async fn upgrade(
     mut req: Request<Body>,
     room: ChatRoom,
@@ -119,7 +111,7 @@ fn convert_key(input: &[u8]) -> String {
     let mut sha1 = Sha1::default();
     sha1.update(input);
     sha1.update(WS_GUID);
-    base64::encode(&sha1.finalize())
+    base64::encode(sha1.finalize())
 }
 
 async fn handle_websocket(
@@ -152,7 +144,7 @@ async fn handle_websocket(
         }
     };
 
-    let _ = room.send(Message::Text(format!("{} joined the room", username)));
+    room.send(Message::Text(format!("{} joined the room", username)));
 
     let handle = tokio::spawn(send_task(room_receiver, tx));
     receive_task(room, username, rx).await;
@@ -162,7 +154,7 @@ async fn handle_websocket(
 
 async fn send_task(mut room_receiver: RoomReceiver, tx: SenderHandle) {
     while let Ok(msg) = room_receiver.recv().await {
-        if let Err(_) = tx.send(msg).await {
+        if tx.send(msg).await.is_err() {
             break;
         }
     }